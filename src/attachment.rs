@@ -0,0 +1,140 @@
+use crate::codeblock_builder::get_language_name;
+use globset::Glob;
+use ignore::WalkBuilder;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// how much file content can be attached to a single question
+pub const ATTACHMENT_BUDGET: usize = 100 * 1024;
+
+/**
+ * Find the files a pattern refers to: the file itself, every file under a directory, or every file
+ * matching a glob(example: "src/**/*.rs"). Directories and globs skip files ignored by .gitignore
+ */
+fn find_files(pattern: &str) -> Result<Vec<PathBuf>, String> {
+    let path = Path::new(pattern);
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    // walk from the longest leading part of the pattern that has no glob characters
+    let is_glob = |component: &&str| component.contains(|ch| "*?[{".contains(ch));
+    let components: Vec<&str> = pattern.split('/').collect();
+    let literal_len = components.iter().take_while(|c| !is_glob(c)).count();
+    let (root, matcher) = if path.is_dir() || literal_len == components.len() {
+        (pattern.to_string(), None)
+    } else {
+        let glob = match Glob::new(pattern.trim_start_matches("./")) {
+            Ok(glob) => glob.compile_matcher(),
+            Err(e) => return Err(format!("invalid glob {}: {}", pattern, e)),
+        };
+        let root = components[..literal_len].join("/");
+        (
+            if root.is_empty() {
+                ".".to_string()
+            } else {
+                root
+            },
+            Some(glob),
+        )
+    };
+    if !Path::new(&root).is_dir() {
+        return Err(format!("{} does not exist", pattern));
+    }
+
+    let mut files: Vec<PathBuf> = WalkBuilder::new(&root)
+        .require_git(false)
+        .build()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|t| t.is_file()).unwrap_or(false))
+        .map(|entry| entry.into_path())
+        .filter(|path| match &matcher {
+            Some(matcher) => matcher.is_match(path.strip_prefix("./").unwrap_or(path)),
+            None => true,
+        })
+        .collect();
+    files.sort();
+    return Ok(files);
+}
+
+/**
+ * Read files matching a pattern and wrap each one in a fenced code block labeled with its path and
+ * language. Binary files are skipped, and files are attached until `budget` bytes are used up. A
+ * summary of what was attached and skipped is printed to stderr, so it stays out of piped output.
+ *
+ * returns the fenced code blocks
+ */
+pub fn collect_attachments(pattern: &str, budget: usize) -> Vec<String> {
+    let files = match find_files(pattern) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            return Vec::new();
+        }
+    };
+    if files.is_empty() {
+        eprintln!("No files match {}", pattern);
+    }
+
+    let mut attachments: Vec<String> = Vec::new();
+    let mut used = 0;
+    for path in files {
+        let display_path = path
+            .strip_prefix("./")
+            .unwrap_or(&path)
+            .display()
+            .to_string();
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("  skipped {}: {}", display_path, e);
+                continue;
+            }
+        };
+        // the same heuristic git uses: a NUL byte in the first 8KB means binary
+        let content = match String::from_utf8(data) {
+            Ok(content) if !content.as_bytes().iter().take(8000).any(|b| *b == 0) => content,
+            _ => {
+                eprintln!("  skipped {}: binary file", display_path);
+                continue;
+            }
+        };
+        if used + content.len() > budget {
+            eprintln!(
+                "  skipped {}: {} bytes would exceed the attachment budget({} bytes left)",
+                display_path,
+                content.len(),
+                budget - used
+            );
+            continue;
+        }
+
+        let language = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(get_language_name)
+            .unwrap_or("");
+        // the fence is longer than any run of backticks in the file, so the file can't close it
+        let mut longest_run = 0;
+        let mut run = 0;
+        for ch in content.chars() {
+            run = if ch == '`' { run + 1 } else { 0 };
+            longest_run = std::cmp::max(longest_run, run);
+        }
+        let fence = "`".repeat(std::cmp::max(3, longest_run + 1));
+        let mut attachment = format!("{}{} {}\n{}", fence, language, display_path, content);
+        if !content.ends_with('\n') {
+            attachment.push('\n');
+        }
+        attachment.push_str(&fence);
+        used += content.len();
+        eprintln!(
+            "  attached {} ({} lines, {} bytes)",
+            display_path,
+            content.lines().count(),
+            content.len()
+        );
+        attachments.push(attachment);
+    }
+    return attachments;
+}
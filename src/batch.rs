@@ -0,0 +1,270 @@
+use crate::llm::{CopilotChat, LLMMessage, LLMRole, LLM};
+use serde_json::{Map, Value};
+use std::collections::{HashSet, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+// request parameters a batch line can't override, the batch runner needs them as they are
+const RESERVED_PARAMS: [&str; 3] = ["messages", "stream", "n"];
+
+/**
+ * One line of a batch file(example: {"id": "a", "prompt": "...", "system": "...", "model": "gpt-4",
+ * "params": {"temperature": 0.5}}). Only the prompt is required, the id defaults to the line number.
+ */
+struct BatchJob {
+    id: Value,
+    prompt: String,
+    system: Option<String>,
+    model: Option<String>,
+    params: Map<String, Value>,
+}
+
+/**
+ * The output file used when none is given(example: "prompts.jsonl" gives "prompts.results.jsonl")
+ */
+pub fn default_output_path(input_path: &str) -> String {
+    let stem = input_path
+        .strip_suffix(".jsonl")
+        .or(input_path.strip_suffix(".json"))
+        .unwrap_or(input_path);
+    return format!("{}.results.jsonl", stem);
+}
+
+fn parse_job(line_number: usize, line: &str) -> Result<BatchJob, (Value, String)> {
+    let json: Value = match serde_json::from_str(line) {
+        Ok(json) => json,
+        Err(e) => return Err((Value::from(line_number), format!("invalid json: {}", e))),
+    };
+    let id = match json.get("id") {
+        Some(id) if !id.is_null() => id.clone(),
+        _ => Value::from(line_number),
+    };
+    let prompt = match json["prompt"].as_str() {
+        Some(prompt) => prompt.to_string(),
+        None => return Err((id, "no \"prompt\" string".to_string())),
+    };
+    let params = match json.get("params") {
+        Some(Value::Object(params)) => params.clone(),
+        Some(_) => return Err((id, "\"params\" must be an object".to_string())),
+        None => Map::new(),
+    };
+    return Ok(BatchJob {
+        id,
+        prompt,
+        system: json["system"].as_str().map(|system| system.to_string()),
+        model: json["model"].as_str().map(|model| model.to_string()),
+        params,
+    });
+}
+
+/**
+ * The ids that already have a successful result in the output file, so that an interrupted batch
+ * picks up where it left off. Failed lines are run again.
+ */
+fn read_finished_ids(output_path: &str) -> HashSet<String> {
+    let mut finished: HashSet<String> = HashSet::new();
+    let content = match fs::read_to_string(output_path) {
+        Ok(content) => content,
+        Err(_) => return finished,
+    };
+    for line in content.lines() {
+        // the last line may be cut off if the batch was killed while writing it
+        let result: Value = match serde_json::from_str(line) {
+            Ok(result) => result,
+            Err(_) => continue,
+        };
+        if result["status"] == "ok" {
+            finished.insert(result["id"].to_string());
+        }
+    }
+    return finished;
+}
+
+fn error_result(id: Value, error: &str) -> Value {
+    return serde_json::json!({
+        "id": id,
+        "status": "error",
+        "error": error,
+    });
+}
+
+/**
+ * Run one job, with its own system prompt, model and parameters. The copilot's defaults are
+ * restored afterwards, since workers reuse their copilot for every job they take.
+ */
+fn run_job(copilot: &mut CopilotChat, job: &BatchJob) -> Value {
+    let mut overrides: Vec<(&str, Value)> = Vec::new();
+    if let Some(model) = &job.model {
+        overrides.push(("model", Value::from(model.as_str())));
+    }
+    for (name, value) in &job.params {
+        if !RESERVED_PARAMS.contains(&name.as_str()) {
+            overrides.push((name, value.clone()));
+        }
+    }
+    let defaults: Vec<(&str, Value)> = overrides
+        .iter()
+        .map(|(name, _)| (*name, copilot.param(name)))
+        .collect();
+    for (name, value) in overrides {
+        copilot.set_param(name, value);
+    }
+
+    let mut history: Vec<LLMMessage> = Vec::new();
+    if let Some(system) = &job.system {
+        history.push(LLMMessage {
+            owner: LLMRole::System,
+            content: system.clone(),
+        });
+    }
+    history.push(LLMMessage {
+        owner: LLMRole::User,
+        content: job.prompt.clone(),
+    });
+
+    let start = Instant::now();
+    let response = copilot.query(&history, |_| {});
+    let latency_ms = start.elapsed().as_millis() as u64;
+
+    let result = match response {
+        Ok(answer) => serde_json::json!({
+            "id": job.id,
+            "status": "ok",
+            "answer": answer,
+            "model": copilot.model(),
+            "usage": copilot.last_usage(),
+            "latency_ms": latency_ms,
+        }),
+        Err(e) => {
+            let mut result = error_result(job.id.clone(), &e.to_string());
+            result["latency_ms"] = Value::from(latency_ms);
+            result
+        }
+    };
+
+    for (name, value) in defaults {
+        copilot.set_param(name, value);
+    }
+    return result;
+}
+
+/**
+ * Run every prompt of a JSONL file and append one result line per prompt to the output file:
+ * {"id", "status": "ok" or "error", "answer" or "error", "model", "usage", "latency_ms"}.
+ * Results are written as they finish, so their order may differ from the input's. Prompts that
+ * already have an "ok" result in the output file are skipped. Progress goes to stderr.
+ *
+ * @param concurrency: how many prompts are in flight at once
+ */
+pub fn run_batch(input_path: &str, output_path: &str, concurrency: usize) {
+    let input = match fs::read_to_string(input_path) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("Error: Could not read {}: {}", input_path, e);
+            return;
+        }
+    };
+    let finished = read_finished_ids(output_path);
+
+    let mut jobs: VecDeque<BatchJob> = VecDeque::new();
+    let mut invalid: Vec<Value> = Vec::new();
+    let mut seen: HashSet<String> = HashSet::new();
+    let mut skipped = 0;
+    for (i, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_job(i + 1, line) {
+            Ok(job) => {
+                let key = job.id.to_string();
+                if finished.contains(&key) {
+                    skipped += 1;
+                } else if !seen.insert(key) {
+                    eprintln!("  skipped line {}: duplicate id {}", i + 1, job.id);
+                } else {
+                    jobs.push_back(job);
+                }
+            }
+            Err((id, e)) => {
+                if !finished.contains(&id.to_string()) {
+                    invalid.push(error_result(id, &format!("line {}: {}", i + 1, e)));
+                }
+            }
+        }
+    }
+    if skipped > 0 {
+        eprintln!(
+            "{} prompts already have results in {}",
+            skipped, output_path
+        );
+    }
+
+    let mut output = match OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(output_path)
+    {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("Error: Could not open {}: {}", output_path, e);
+            return;
+        }
+    };
+    // finish a line cut off by an interrupted run, so that the next result starts on its own line
+    let cut_off = fs::read_to_string(output_path)
+        .map(|content| !content.is_empty() && !content.ends_with('\n'))
+        .unwrap_or(false);
+    if cut_off {
+        let _ = writeln!(output);
+    }
+
+    let total = jobs.len() + invalid.len();
+    let mut done = 0;
+    let mut write_result = |result: &Value| {
+        done += 1;
+        if let Err(e) = writeln!(output, "{}", result).and_then(|_| output.flush()) {
+            eprintln!("Error: Could not write {}: {}", output_path, e);
+        }
+        let status = match result["error"].as_str() {
+            Some(error) => format!("error: {}", error),
+            None => format!("ok in {}ms", result["latency_ms"]),
+        };
+        eprintln!("[{}/{}] {} {}", done, total, result["id"], status);
+    };
+    for result in &invalid {
+        write_result(result);
+    }
+
+    let workers = std::cmp::min(std::cmp::max(concurrency, 1), jobs.len());
+    let jobs = Arc::new(Mutex::new(jobs));
+    let (sender, receiver) = mpsc::channel::<Value>();
+    let mut handles = Vec::new();
+    for _ in 0..workers {
+        let jobs = Arc::clone(&jobs);
+        let sender = sender.clone();
+        handles.push(thread::spawn(move || {
+            let mut copilot = CopilotChat::new();
+            loop {
+                let job = match jobs.lock().unwrap().pop_front() {
+                    Some(job) => job,
+                    None => break,
+                };
+                if sender.send(run_job(&mut copilot, &job)).is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+    drop(sender);
+
+    for result in receiver {
+        write_result(&result);
+    }
+    for handle in handles {
+        let _ = handle.join();
+    }
+}
@@ -1,118 +1,207 @@
-use crate::codeblock_builder::{CodeBlockBuilder, CodeBlockBuilderState};
+use crate::attachment;
+use crate::codeblock_builder::{self, CodeBlock, CodeBlockBuilder, CodeBlockBuilderState};
+use crate::config;
+use crate::conversation::ConversationTree;
+use crate::export::{self, ExportFormat};
 use crate::llm::{CopilotChat, LLMMessage, LLMRole, LLM};
+use crate::markdown::MarkdownRenderer;
 use crate::syntax;
+use std::fs::{self, File};
 use std::io::{self, Write};
+use std::os::unix::io::FromRawFd;
 use std::sync::Mutex;
-use termion::{clear, cursor, terminal_size};
+use std::time::Instant;
+use syntect::easy::HighlightLines;
+
+/**
+ * How responses are printed
+ */
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputMode {
+    Pretty, // wrapped and syntax highlighted, for a terminal
+    Raw,    // the model's text as is
+    Json,   // one json document per answer, printed once the answer is complete
+    Ndjson, // one json event per line, streamed as the answer comes in
+}
 
 struct LLMResponsePrinter {
-    line_buffer: String,
-    word_buffer: String,
-    line_buffer_unflushed_begin: usize,
+    markdown: MarkdownRenderer, // prints the prose around code blocks
     codeblock_builder: CodeBlockBuilder,
-    line_width: usize,
+    highlighter: Option<HighlightLines<'static>>, // highlights the code block being printed
+    code_blocks: Vec<CodeBlock>, // every code block printed in this session, in order
+    last_prose_line: String, // last non-empty markdown line, may name the next code block's file
+    output_mode: OutputMode,
+    first_token_at: Option<Instant>, // when the first piece of the current response arrived
 }
 
 impl LLMResponsePrinter {
+    /**
+     * Forget what the previous response left behind: a code block it didn't close(example: the
+     * answer was cut off) doesn't swallow this one, and its highlighting state doesn't leak in
+     */
+    fn start_response(&mut self) {
+        self.first_token_at = None;
+        self.highlighter = None;
+        self.codeblock_builder.reset();
+        // a response starts at the beginning of a line, so it may open with a code block
+        self.codeblock_builder.build_codeblock_from_char('\n');
+    }
+
     fn llm_response_callback(&mut self, response: &str) {
-        fn push_word_buffer(word_buffer_ref: &mut String, line_buffer_ref: &mut String) {
-            line_buffer_ref.push_str(word_buffer_ref);
-            word_buffer_ref.clear();
+        if self.first_token_at.is_none() {
+            self.first_token_at = Some(Instant::now());
         }
-
-        fn print_line_buffer(line_buffer: &String, begin: usize) {
-            let line =
-                syntax::get_syntax_highlighted_code_line(line_buffer.as_str(), "md", Some(begin));
-            print!("{}", cursor::Left(999));
-            print!("{}", clear::UntilNewline);
-            print!("{}", line);
-            std::io::stdout().flush().unwrap();
+        if self.output_mode != OutputMode::Pretty {
+            if self.output_mode == OutputMode::Raw {
+                print_answer(response);
+            }
+            if self.output_mode == OutputMode::Ndjson {
+                print_event(serde_json::json!({ "type": "delta", "content": response }));
+            }
+            // still keep track of code blocks, so that they can be yanked
+            for ch in response.chars() {
+                if let Some(code_block) = self.codeblock_builder.build_codeblock_from_char(ch).1 {
+                    if self.output_mode == OutputMode::Ndjson {
+                        let mut event = code_block_to_json(&code_block);
+                        event["type"] = serde_json::json!("code_block");
+                        event["index"] = serde_json::json!(self.code_blocks.len() + 1);
+                        print_event(event);
+                    }
+                    self.code_blocks.push(code_block);
+                }
+            }
+            io::stdout().flush().unwrap();
+            return;
         }
 
         for ch in response.chars() {
-            let char_is_md: bool;
-            let res = self.codeblock_builder.build_codeblock_from_char(ch);
-            match res.0 {
-                CodeBlockBuilderState::EatingCode => {
-                    char_is_md = false;
-                }
+            let (state, finished_block, code_line) =
+                self.codeblock_builder.build_codeblock_from_char(ch);
+            let char_is_md = match state {
+                CodeBlockBuilderState::EatingCode => false,
                 CodeBlockBuilderState::BeginEatingCode => {
                     // print the ```(lang) line at the beginning of the code block
-                    push_word_buffer(&mut self.word_buffer, &mut self.line_buffer);
-                    print_line_buffer(&self.line_buffer, self.line_buffer_unflushed_begin);
-                    self.line_buffer.clear();
-                    self.line_buffer_unflushed_begin = 0;
-                    char_is_md = false;
-                }
-                _ => {
-                    char_is_md = true;
+                    self.markdown.end_line();
+                    false
                 }
-            }
-            match res.1 {
-                Some(code_block) => {
-                    //TODO: create app object to manage states
-                }
-                None => {}
-            }
-            match res.2 {
+                _ => true,
+            };
+            match code_line {
                 Some(code_line_and_language) => {
-                    syntax::print_syntax_highlighted_code_line(
-                        code_line_and_language.0.as_str(),
-                        code_line_and_language.1.as_str(),
-                        Some(0),
-                    );
+                    let extension = code_line_and_language.1.as_str();
+                    let language = self.codeblock_builder.language();
+                    let highlighter = self
+                        .highlighter
+                        .get_or_insert_with(|| syntax::new_highlighter(extension, language));
+                    match finished_block {
+                        Some(mut code_block) => {
+                            if code_block.path.is_none() {
+                                code_block.path =
+                                    codeblock_builder::find_file_path(&self.last_prose_line);
+                            }
+                            self.last_prose_line.clear();
+                            // label the closing ``` line with the code block's number. The
+                            // fence isn't code, it would be highlighted as a part of whatever
+                            // construct the block left open
+                            self.code_blocks.push(code_block);
+                            println!(
+                                "\x1b[2m{} [{}]\x1b[0m",
+                                code_line_and_language.0.trim_end_matches('\n'),
+                                self.code_blocks.len()
+                            );
+                            // the next code block starts highlighting from scratch
+                            self.highlighter = None;
+                        }
+                        None => {
+                            syntax::print_syntax_highlighted_code_line(
+                                highlighter,
+                                code_line_and_language.0.as_str(),
+                                Some(0),
+                            );
+                        }
+                    }
                 }
                 None => {}
             }
 
             if char_is_md {
-                // not code block. pirnt as markdown
+                // not code block. print as markdown
+                if let Some(line) = self.markdown.push(ch) {
+                    if !line.trim().is_empty() {
+                        self.last_prose_line = line;
+                    }
+                }
+            }
+        }
 
-                let size = terminal_size();
-                let w = size.unwrap().0;
-                let line_width_limit = std::cmp::min(w as usize, self.line_width);
-                let curr_line_size: usize =
-                    self.line_buffer.len() - self.line_buffer_unflushed_begin;
-                self.word_buffer.push(ch);
+        io::stdout().flush().unwrap();
+    }
+}
 
-                let curr_word_size: usize = self.word_buffer.len();
-                let line_too_long_with_new_word =
-                    curr_line_size + curr_word_size >= line_width_limit as usize;
+/**
+ * First line of a message, truncated so that it fits in a listing
+ */
+fn preview(content: &str) -> String {
+    let first_line = content.trim().lines().next().unwrap_or("");
+    if first_line.chars().count() > 60 {
+        let truncated: String = first_line.chars().take(57).collect();
+        return format!("{}...", truncated);
+    }
+    return first_line.to_string();
+}
 
-                let should_push_word = ch == ' ' || ch == '\n';
+fn code_block_to_json(code_block: &CodeBlock) -> serde_json::Value {
+    return serde_json::json!({
+        "language": code_block.language,
+        "extension": code_block.language_extension,
+        "path": code_block.path,
+        "code": code_block.code,
+    });
+}
 
-                // pushing the word does not make the line too long
-                if should_push_word && !line_too_long_with_new_word {
-                    push_word_buffer(&mut self.word_buffer, &mut self.line_buffer);
-                }
-                // print the line buffer and set index to the end of the line buffer
-                if line_too_long_with_new_word {
-                    // we don't clear the buffer here here because we need the line buffer as context
-                    // infor for syntax highlighting
-                    print_line_buffer(&self.line_buffer, self.line_buffer_unflushed_begin);
-                    println!(); // manually insert a new line
-                    self.line_buffer_unflushed_begin = self.line_buffer.len();
-                } else if ch == '\n' {
-                    print_line_buffer(&self.line_buffer, self.line_buffer_unflushed_begin);
-                    self.line_buffer.clear();
-                    self.line_buffer_unflushed_begin = 0;
-                } else {
-                    print!("{}", ch); // simply prints out the char, which will get erased once
-                                      // the syntax-highlighted line is printed
-                }
-                // push the word after the line is printed
-                if should_push_word && line_too_long_with_new_word {
-                    push_word_buffer(&mut self.word_buffer, &mut self.line_buffer);
-                }
-            }
+// where answers go in the machine-readable modes, stdout unless `send_chatter_to_stderr` moved it
+static ANSWER_OUTPUT: Mutex<Option<File>> = Mutex::new(None);
+
+/**
+ * Print a piece of an answer in a machine-readable mode(raw text or json)
+ */
+fn print_answer(text: &str) {
+    match ANSWER_OUTPUT.lock().unwrap().as_mut() {
+        Some(output) => {
+            let _ = output.write_all(text.as_bytes());
+            let _ = output.flush();
+        }
+        None => {
+            print!("{}", text);
+            io::stdout().flush().unwrap();
         }
+    }
+}
 
-        io::stdout().flush().unwrap();
+/**
+ * Print a json value on its own line, for machine-readable output
+ */
+fn print_event(event: serde_json::Value) {
+    print_answer(&format!("{}\n", event));
+}
+
+/**
+ * Keep stdout for the answers and send everything else(prompts, command output, errors) to
+ * stderr, so that the stdout of an interactive session in a machine-readable mode can be parsed
+ */
+pub fn send_chatter_to_stderr() {
+    io::stdout().flush().unwrap();
+    let answers = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if answers < 0 || unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        eprintln!("Error: Could not separate the answers from the rest of the output");
+        return;
     }
+    *ANSWER_OUTPUT.lock().unwrap() = Some(unsafe { File::from_raw_fd(answers) });
 }
 
 pub struct Chat {
-    chat_history: Vec<LLMMessage>,
+    chat_history: ConversationTree,
+    attachments: Vec<String>, // context waiting to be sent along with the next question
     name: String,
     copilot: CopilotChat,
     response_handler: Mutex<LLMResponsePrinter>,
@@ -121,15 +210,18 @@ pub struct Chat {
 impl Chat {
     pub fn new() -> Chat {
         Chat {
-            chat_history: Vec::new(),
+            chat_history: ConversationTree::new(),
+            attachments: Vec::new(),
             name: String::from("Chat"),
             copilot: CopilotChat::new(),
             response_handler: Mutex::new(LLMResponsePrinter {
-                word_buffer: String::new(),
-                line_buffer: String::new(),
-                line_buffer_unflushed_begin: 0,
+                markdown: MarkdownRenderer::new(config::get().line_width),
                 codeblock_builder: CodeBlockBuilder::new(),
-                line_width: 80,
+                highlighter: None,
+                code_blocks: Vec::new(),
+                last_prose_line: String::new(),
+                output_mode: OutputMode::Pretty,
+                first_token_at: None,
             }),
         }
     }
@@ -138,18 +230,39 @@ impl Chat {
      * Ask the assistant a question, and return the response
      */
     pub fn ask(&mut self, question: &str) -> String {
+        let mut content = String::new();
+        for attachment in &self.attachments {
+            content.push_str(attachment);
+            content.push_str("\n\n");
+        }
+        content.push_str(question);
         self.chat_history.push(LLMMessage {
             owner: LLMRole::User,
-            content: question.to_string(),
+            content,
         });
 
-        let response = self.copilot.query(&self.chat_history, |response| {
-            self.response_handler.lock().unwrap().llm_response_callback(response);
-        });
+        let output_mode = self.response_handler.lock().unwrap().output_mode;
+        let code_blocks_before = self.response_handler.lock().unwrap().code_blocks.len();
+        self.response_handler.lock().unwrap().start_response();
+        let start = Instant::now();
+
+        let response = self
+            .copilot
+            .query(&self.chat_history.history(), |response| {
+                self.response_handler
+                    .lock()
+                    .unwrap()
+                    .llm_response_callback(response);
+            });
 
         let ai_response;
         match response {
             Ok(msg) => {
+                // attachments are kept for the next question when the request fails
+                self.attachments.clear();
+                if output_mode == OutputMode::Pretty {
+                    self.response_handler.lock().unwrap().markdown.finish();
+                }
                 self.chat_history.push(LLMMessage {
                     owner: LLMRole::Assistant,
                     content: msg.clone(),
@@ -157,25 +270,405 @@ impl Chat {
                 ai_response = msg;
             }
             Err(e) => {
-                match e.status() {
+                let error = match e.status() {
                     Some(status_code) => match status_code.as_u16() {
-                        400 => {
-                            println!(
-                                "Request rejected by API with code 400. Consider asking again."
-                            )
-                        }
-                        _ => {
-                            println!("Error: {}", status_code);
-                        }
+                        400 => "Request rejected by API with code 400. Consider asking again."
+                            .to_string(),
+                        _ => format!("Error: {}", status_code),
                     },
-                    None => {
-                        println!("Unknown error when executing copilot query.");
+                    None => "Unknown error when executing copilot query.".to_string(),
+                };
+                match output_mode {
+                    OutputMode::Json => print_event(serde_json::json!({ "error": error })),
+                    OutputMode::Ndjson => {
+                        print_event(serde_json::json!({ "type": "error", "error": error }))
                     }
+                    _ => println!("{}", error),
+                }
+                // the question wasn't answered, don't leave it in the conversation
+                self.chat_history.pop();
+                if !self.attachments.is_empty() && output_mode == OutputMode::Pretty {
+                    println!("The attachments are kept for the next question");
                 }
-                ai_response = "".to_string();
+                return "".to_string();
+            }
+        }
+
+        if output_mode == OutputMode::Json || output_mode == OutputMode::Ndjson {
+            let printer = self.response_handler.lock().unwrap();
+            let timings = serde_json::json!({
+                "first_token_ms": printer
+                    .first_token_at
+                    .map(|at| at.duration_since(start).as_millis() as u64),
+                "total_ms": start.elapsed().as_millis() as u64,
+            });
+            if output_mode == OutputMode::Json {
+                let code_blocks: Vec<serde_json::Value> = printer.code_blocks[code_blocks_before..]
+                    .iter()
+                    .map(code_block_to_json)
+                    .collect();
+                print_event(serde_json::json!({
+                    "model": self.copilot.model(),
+                    "answer": ai_response,
+                    "code_blocks": code_blocks,
+                    "usage": self.copilot.last_usage(),
+                    "timings": timings,
+                }));
+            } else {
+                print_event(serde_json::json!({
+                    "type": "done",
+                    "model": self.copilot.model(),
+                    "usage": self.copilot.last_usage(),
+                    "timings": timings,
+                }));
             }
         }
 
         return ai_response;
     }
+
+    /**
+     * Set how responses are printed
+     */
+    pub fn set_output_mode(&mut self, output_mode: OutputMode) {
+        self.response_handler.lock().unwrap().output_mode = output_mode;
+    }
+
+    /**
+     * Attach context(files, diffs...) to the next question
+     */
+    pub fn attach(&mut self, context: String) {
+        self.attachments.push(context);
+    }
+
+    /**
+     * Number of bytes that can still be attached to the next question
+     */
+    pub fn attachment_budget_left(&self) -> usize {
+        let used: usize = self.attachments.iter().map(|a| a.len()).sum();
+        return attachment::ATTACHMENT_BUDGET.saturating_sub(used);
+    }
+
+    /**
+     * Ask a new version of an earlier question. The new question and its answer are put on a new
+     * branch forking from the original question's parent, the original branch is kept.
+     */
+    pub fn edit(&mut self, turn_id: usize, question: &str) -> Option<String> {
+        if !self.chat_history.fork(turn_id) {
+            println!("Turn {} is not a question", turn_id);
+            return None;
+        }
+        return Some(self.ask(question));
+    }
+
+    /**
+     * Make the given turn the head of the conversation, the next question continues from it
+     */
+    pub fn checkout(&mut self, turn_id: usize) -> bool {
+        if !self.chat_history.checkout(turn_id) {
+            println!("Turn {} does not exist", turn_id);
+            return false;
+        }
+        self.print_path();
+        return true;
+    }
+
+    /**
+     * Print the tip of every branch, marking the one currently checked out
+     */
+    pub fn print_branches(&self) {
+        let path = self.chat_history.path();
+        for leaf in self.chat_history.leaves() {
+            let turn = self.chat_history.get(leaf).unwrap();
+            let marker = if path.contains(&leaf) { "*" } else { " " };
+            println!(
+                "{} [{}] {}: {}",
+                marker,
+                leaf,
+                turn.message.owner.as_str(),
+                preview(&turn.message.content)
+            );
+        }
+    }
+
+    /**
+     * Print the turns from the root of the conversation to the head
+     */
+    pub fn print_path(&self) {
+        for id in self.chat_history.path() {
+            let turn = self.chat_history.get(id).unwrap();
+            let forks = if turn.children.len() > 1 {
+                format!(" ({} branches)", turn.children.len())
+            } else {
+                String::new()
+            };
+            println!(
+                "[{}] {}: {}{}",
+                id,
+                turn.message.owner.as_str(),
+                preview(&turn.message.content),
+                forks
+            );
+        }
+    }
+
+    /**
+     * Serialize the chat into the session format
+     */
+    pub fn to_json(&self) -> serde_json::Value {
+        return serde_json::json!({
+            "name": self.name,
+            "conversation": self.chat_history.to_json(),
+        });
+    }
+
+    /**
+     * The conversation up to the current turn and every code block, as json for external commands:
+     * {"model", "history": [{"role", "content"}], "code_blocks": [{"language", "extension", "path",
+     * "code"}]}
+     */
+    pub fn context_json(&self) -> serde_json::Value {
+        let history: Vec<serde_json::Value> = self
+            .chat_history
+            .history()
+            .iter()
+            .map(|message| {
+                serde_json::json!({
+                    "role": message.owner.as_str(),
+                    "content": message.content,
+                })
+            })
+            .collect();
+        let code_blocks: Vec<serde_json::Value> = self
+            .get_code_blocks()
+            .iter()
+            .map(code_block_to_json)
+            .collect();
+        return serde_json::json!({
+            "model": self.model(),
+            "history": history,
+            "code_blocks": code_blocks,
+        });
+    }
+
+    /**
+     * Write the session to a json file
+     */
+    pub fn save(&self, path: &str) -> bool {
+        let data = serde_json::to_string_pretty(&self.to_json()).unwrap();
+        match fs::write(path, data) {
+            Ok(_) => return true,
+            Err(e) => {
+                println!("Error: Could not write session to {}: {}", path, e);
+                return false;
+            }
+        }
+    }
+
+    /**
+     * Replace the current conversation with the one stored in a session file
+     */
+    pub fn load(&mut self, path: &str) -> bool {
+        match read_session_file(path) {
+            Some((name, tree)) => {
+                self.name = name;
+                self.chat_history = tree;
+                return true;
+            }
+            None => return false,
+        }
+    }
+
+    /**
+     * The last answer on the current branch, None if the model hasn't answered yet
+     */
+    pub fn last_response(&self) -> Option<String> {
+        return self
+            .chat_history
+            .history()
+            .iter()
+            .rev()
+            .find(|message| matches!(message.owner, LLMRole::Assistant))
+            .map(|message| message.content.clone());
+    }
+
+    /**
+     * Add a system message to the conversation, it applies to every question asked after it
+     */
+    pub fn add_system_prompt(&mut self, prompt: &str) {
+        self.chat_history.push(LLMMessage {
+            owner: LLMRole::System,
+            content: prompt.to_string(),
+        });
+    }
+
+    /**
+     * The model questions are sent to
+     */
+    pub fn model(&self) -> String {
+        return self
+            .copilot
+            .param("model")
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+    }
+
+    /**
+     * Send the following questions to another model
+     */
+    pub fn set_model(&mut self, model: &str) {
+        self.copilot.set_param("model", serde_json::json!(model));
+    }
+
+    /**
+     * Get a code block by its label, code blocks are numbered from 1 in the order they were printed
+     */
+    pub fn get_code_block(&self, label: usize) -> Option<CodeBlock> {
+        if label == 0 {
+            return None;
+        }
+        let printer = self.response_handler.lock().unwrap();
+        return printer.code_blocks.get(label - 1).cloned();
+    }
+
+    /**
+     * All code blocks printed in this session, in order
+     */
+    pub fn get_code_blocks(&self) -> Vec<CodeBlock> {
+        return self.response_handler.lock().unwrap().code_blocks.clone();
+    }
+
+    /**
+     * Print the label, language and first line of every code block
+     */
+    pub fn print_code_blocks(&self) {
+        let code_blocks = self.get_code_blocks();
+        if code_blocks.is_empty() {
+            println!("No code blocks yet");
+        }
+        for (i, code_block) in code_blocks.iter().enumerate() {
+            let path = match &code_block.path {
+                Some(path) => format!(" {}", path),
+                None => String::new(),
+            };
+            println!(
+                "[{}] {}{} ({} lines): {}",
+                i + 1,
+                code_block.language_extension,
+                path,
+                code_block.code.lines().count(),
+                preview(&code_block.code)
+            );
+        }
+    }
+
+    /**
+     * Render the current branch of the conversation in the given format
+     */
+    pub fn export(&self, format: &ExportFormat) -> String {
+        return export::export(&self.name, &self.chat_history.history(), format);
+    }
+}
+
+/**
+ * Read a session file written by `Chat::save`
+ * returns the chat's name and its conversation, None if the file can't be read
+ */
+pub fn read_session_file(path: &str) -> Option<(String, ConversationTree)> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Error: Could not read session from {}: {}", path, e);
+            return None;
+        }
+    };
+    let json: serde_json::Value = match serde_json::from_str(&data) {
+        Ok(json) => json,
+        Err(_) => {
+            println!("Error: {} is not a valid session file", path);
+            return None;
+        }
+    };
+    match ConversationTree::from_json(&json["conversation"]) {
+        Some(tree) => {
+            let name = json["name"].as_str().unwrap_or("Chat").to_string();
+            return Some((name, tree));
+        }
+        None => {
+            println!("Error: {} is not a valid session file", path);
+            return None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn printer() -> LLMResponsePrinter {
+        return LLMResponsePrinter {
+            markdown: MarkdownRenderer::new(80),
+            codeblock_builder: CodeBlockBuilder::new(),
+            highlighter: None,
+            code_blocks: Vec::new(),
+            last_prose_line: String::new(),
+            output_mode: OutputMode::Pretty,
+            first_token_at: None,
+        };
+    }
+
+    /**
+     * How `line` is highlighted after the code block's lines so far, by a highlighter that started
+     * with the code block
+     */
+    fn highlighted_in_block(lines: &[&str], line: &str) -> String {
+        let mut highlighter = syntax::new_highlighter("rs", "rust");
+        for line in lines {
+            syntax::get_syntax_highlighted_code_line(&mut highlighter, line, None);
+        }
+        return syntax::get_syntax_highlighted_code_line(&mut highlighter, line, None);
+    }
+
+    /**
+     * How the printer's highlighter highlights the next line of the code block being printed
+     */
+    fn highlighted_by_printer(printer: &mut LLMResponsePrinter, line: &str) -> String {
+        let highlighter = printer.highlighter.as_mut().unwrap();
+        return syntax::get_syntax_highlighted_code_line(highlighter, line, None);
+    }
+
+    #[test]
+    fn open_comment_does_not_leak_into_the_next_code_block() {
+        let mut printer = printer();
+        printer.start_response();
+        printer.llm_response_callback("First:\n```rust\nfn main() {}\n/* never closed\n```\n");
+        assert_eq!(printer.code_blocks.len(), 1);
+        assert!(printer.highlighter.is_none());
+
+        printer.llm_response_callback("\nThen:\n```rust\nlet x = 1;\n");
+        let next_line = highlighted_by_printer(&mut printer, "let y = 2;\n");
+        assert_eq!(
+            next_line,
+            highlighted_in_block(&["let x = 1;\n"], "let y = 2;\n")
+        );
+        // the same line, highlighted as a part of the first block's comment
+        let leaked = highlighted_in_block(&["/* never closed\n", "let x = 1;\n"], "let y = 2;\n");
+        assert_ne!(next_line, leaked);
+    }
+
+    #[test]
+    fn cut_off_code_block_does_not_leak_into_the_next_response() {
+        let mut printer = printer();
+        printer.start_response();
+        printer.llm_response_callback("```rust\n/* the answer was cut off");
+        printer.start_response();
+        printer.llm_response_callback("```rust\nlet x = 1;\n");
+        assert!(printer.code_blocks.is_empty());
+        let next_line = highlighted_by_printer(&mut printer, "let y = 2;\n");
+        assert_eq!(
+            next_line,
+            highlighted_in_block(&["let x = 1;\n"], "let y = 2;\n")
+        );
+    }
 }
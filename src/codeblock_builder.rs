@@ -75,10 +75,88 @@ lazy_static! {
         m.insert("vb.net", "vb");
         m.insert("xquery", "xq");
         m.insert("zsh", "zsh");
+        m.insert("diff", "diff");
+        m.insert("patch", "diff");
         return m;
     };
 }
 
+/**
+ * Map a markdown code block's language tag(example: "rust") to a file extension(example: "rs").
+ * Unknown languages map to "txt"
+ */
+pub fn get_language_extension(language: &str) -> String {
+    return MD_TYPE_TO_EXT.get(language).unwrap_or(&"txt").to_string();
+}
+
+/**
+ * Map a file extension(example: "rs") back to a markdown language tag(example: "rust"). When
+ * several languages share an extension the alphabetically first one is picked, so that the result
+ * is stable
+ */
+pub fn get_language_name(extension: &str) -> Option<&'static str> {
+    return MD_TYPE_TO_EXT
+        .iter()
+        .filter(|(_, ext)| **ext == extension)
+        .map(|(name, _)| *name)
+        .min();
+}
+
+// extensions of files that are commonly written but aren't code block languages
+const OTHER_FILE_EXTENSIONS: [&str; 14] = [
+    "toml", "md", "txt", "lock", "cfg", "ini", "env", "h", "hpp", "cc", "jsx", "tsx", "vue", "yaml",
+];
+const KNOWN_FILE_NAMES: [&str; 5] = [
+    "Makefile",
+    "Dockerfile",
+    "CMakeLists.txt",
+    ".gitignore",
+    ".bashrc",
+];
+
+fn looks_like_file_path(word: &str) -> bool {
+    if word.is_empty() || word.contains("://") {
+        return false;
+    }
+    let file_name = word.rsplit('/').next().unwrap();
+    if KNOWN_FILE_NAMES.contains(&file_name) {
+        return true;
+    }
+    match file_name.rsplit_once('.') {
+        Some((stem, extension)) => {
+            return !stem.is_empty()
+                && (MD_TYPE_TO_EXT.values().any(|ext| *ext == extension)
+                    || OTHER_FILE_EXTENSIONS.contains(&extension));
+        }
+        None => return false,
+    }
+}
+
+/**
+ * Find something that looks like a file path in a piece of text(example: "Create `src/main.rs`
+ * with:" gives "src/main.rs"). Words wrapped in backticks are preferred over bare words.
+ */
+pub fn find_file_path(text: &str) -> Option<String> {
+    let candidates: Vec<(bool, &str)> = text
+        .split_whitespace()
+        .map(|word| {
+            let word = word
+                .trim_matches(|ch: char| "`'\"*:,;()[]".contains(ch))
+                .trim_start_matches("title=")
+                .trim_matches(|ch: char| "`'\"".contains(ch))
+                .trim_end_matches('.');
+            (text.contains(&format!("`{}`", word)), word)
+        })
+        .filter(|(_, word)| looks_like_file_path(word))
+        .collect();
+
+    return candidates
+        .iter()
+        .find(|(quoted, _)| *quoted)
+        .or(candidates.first())
+        .map(|(_, word)| word.to_string());
+}
+
 pub enum CodeBlockBuilderState {
     None,
     EatingBackTicksBegin,
@@ -87,9 +165,12 @@ pub enum CodeBlockBuilderState {
     EndEatingCode,
 }
 
+#[derive(Clone)]
 pub struct CodeBlock {
-    code: String,
-    language_extension: String, // (c, rs, py, etc)
+    pub code: String,
+    pub language: String, // the language tag of the code block's fence (rust, python, etc)
+    pub language_extension: String, // (c, rs, py, etc)
+    pub path: Option<String>, // the file the code block is meant for, if it names one
 }
 
 pub struct CodeBlockBuilder {
@@ -111,11 +192,20 @@ impl CodeBlockBuilder {
             backticks_only_in_curr_line: false,
             curr_code_block: CodeBlock {
                 code: String::new(),
+                language: String::new(),
                 language_extension: String::new(),
+                path: None,
             },
         }
     }
 
+    /**
+     * The language tag of the code block being built(example: "rust")
+     */
+    pub fn language(&self) -> &str {
+        return &self.curr_code_block.language;
+    }
+
     /**
      * Reset the state of the code block builder
      */
@@ -126,7 +216,9 @@ impl CodeBlockBuilder {
         self.code_block_state = CodeBlockBuilderState::None;
         self.backticks_only_in_curr_line = false;
         self.curr_code_block.code.clear();
+        self.curr_code_block.language.clear();
         self.curr_code_block.language_extension.clear();
+        self.curr_code_block.path = None;
     }
 
     /**
@@ -178,10 +270,22 @@ impl CodeBlockBuilder {
                         // we also ate this new line, so append this to the code block.
                         self.code_block_state = CodeBlockBuilderState::BeginEatingCode;
                         self.backticks_count = 0;
-                        self.curr_code_block.language_extension = MD_TYPE_TO_EXT
-                            .get(self.code_block_type_buf.as_str())
-                            .unwrap_or(&"txt")
-                            .to_string();
+                        // the info string is the language, optionally followed by a file name
+                        // (example: ```rust src/main.rs). A lone file name also works
+                        let mut info = self.code_block_type_buf.split_whitespace();
+                        let language = info.next().unwrap_or("");
+                        self.curr_code_block.language = language.to_string();
+                        if looks_like_file_path(language) {
+                            let extension = language.rsplit_once('.').map(|(_, ext)| ext);
+                            self.curr_code_block.language_extension =
+                                extension.unwrap_or("txt").to_string();
+                            self.curr_code_block.path = Some(language.to_string());
+                        } else {
+                            self.curr_code_block.language_extension =
+                                get_language_extension(language);
+                            self.curr_code_block.path =
+                                find_file_path(&info.collect::<Vec<&str>>().join(" "));
+                        }
                         self.curr_code_block.code.clear();
                         self.code_line_buf.push('\n');
                     } else {
@@ -214,9 +318,13 @@ impl CodeBlockBuilder {
                     '\n' => {
                         if self.backticks_only_in_curr_line && self.backticks_count >= 3 {
                             // end of code block
+                            // the first code line carries the new line ending the ```(lang) line
+                            let code = &self.curr_code_block.code;
                             code_block = Some(CodeBlock {
-                                code: self.curr_code_block.code.clone(),
+                                code: code.strip_prefix('\n').unwrap_or(code).to_string(),
+                                language: self.curr_code_block.language.clone(),
                                 language_extension: self.curr_code_block.language_extension.clone(),
+                                path: self.curr_code_block.path.clone(),
                             });
                             self.code_block_state = CodeBlockBuilderState::EndEatingCode;
                             self.code_block_type_buf.clear();
@@ -0,0 +1,265 @@
+use crate::chat::Chat;
+
+/**
+ * An argument a command takes, with the name shown in its usage
+ */
+pub enum ArgSpec {
+    Number(&'static str),       // a code block number, 1 or more
+    TurnId(&'static str),       // a turn of the conversation as \path shows it, 0 or more
+    Word(&'static str),         // a single word
+    OptionalWord(&'static str), // a single word that may be left out
+    Text(&'static str),         // the rest of the line, must come last
+    OptionalText(&'static str), // the rest of the line, may be empty
+}
+
+impl ArgSpec {
+    fn usage(&self) -> String {
+        return match self {
+            ArgSpec::Number(name) | ArgSpec::TurnId(name) => name.to_string(),
+            ArgSpec::Word(name) | ArgSpec::Text(name) => format!("<{}>", name),
+            ArgSpec::OptionalWord(name) | ArgSpec::OptionalText(name) => format!("[{}]", name),
+        };
+    }
+}
+
+/**
+ * What the loop does once a command ran
+ */
+pub enum CommandResult {
+    Done,           // prompt for the next input
+    Send(String),   // send this to the copilot, as if it had been typed
+    Insert(String), // put this in the next prompt, for the user to finish
+    Quit,
+}
+
+// gets one string per ArgSpec, empty if an optional argument was left out
+pub type CommandFn = dyn Fn(&mut Chat, &[String]) -> CommandResult;
+
+pub struct Command {
+    pub name: &'static str, // without the backslash(example: "y" for \y)
+    pub aliases: &'static [&'static str],
+    pub args: &'static [ArgSpec],
+    pub help: &'static str,
+    pub run: Box<CommandFn>,
+}
+
+impl Command {
+    /**
+     * The command as it's typed(example: "\w N [file]")
+     */
+    pub fn usage(&self) -> String {
+        let mut usage = format!("\\{}", self.name);
+        for arg in self.args {
+            usage.push(' ');
+            usage.push_str(&arg.usage());
+        }
+        return usage;
+    }
+
+    /**
+     * Split the text after the command into its arguments
+     * returns one string per ArgSpec, or why the text doesn't fit them
+     */
+    fn parse_args(&self, text: &str) -> Result<Vec<String>, String> {
+        let mut rest = text.trim();
+        let mut args: Vec<String> = Vec::new();
+        for spec in self.args {
+            let value = match spec {
+                ArgSpec::Text(_) | ArgSpec::OptionalText(_) => {
+                    let value = rest;
+                    rest = "";
+                    value
+                }
+                _ => {
+                    let (value, remainder) =
+                        rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                    rest = remainder.trim_start();
+                    value
+                }
+            };
+            match spec {
+                ArgSpec::Number(name) => match value.parse::<usize>() {
+                    Ok(n) if n > 0 => {}
+                    _ if value.is_empty() => return Err(format!("{} is missing", name)),
+                    _ => return Err(format!("{} must be a number, got {}", name, value)),
+                },
+                ArgSpec::TurnId(name) => match value.parse::<usize>() {
+                    Ok(_) => {}
+                    _ if value.is_empty() => return Err(format!("{} is missing", name)),
+                    _ => return Err(format!("{} must be a turn id, got {}", name, value)),
+                },
+                ArgSpec::Word(name) | ArgSpec::Text(name) if value.is_empty() => {
+                    return Err(format!("<{}> is missing", name));
+                }
+                _ => {}
+            }
+            args.push(value.to_string());
+        }
+        if !rest.is_empty() {
+            return Err(format!("unexpected {}", rest));
+        }
+        return Ok(args);
+    }
+}
+
+pub struct CommandRegistry {
+    commands: Vec<Command>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> CommandRegistry {
+        return CommandRegistry {
+            commands: Vec::new(),
+        };
+    }
+
+    /**
+     * Add a command. A command whose name or aliases are already taken is not added.
+     *
+     * returns true if the command was added
+     */
+    pub fn register(&mut self, command: Command) -> bool {
+        let mut names = std::iter::once(&command.name).chain(command.aliases.iter());
+        if names.any(|name| self.is_taken(name)) {
+            return false;
+        }
+        self.commands.push(command);
+        return true;
+    }
+
+    /**
+     * Whether a name is already used by a command or an alias, \h included
+     */
+    pub fn is_taken(&self, name: &str) -> bool {
+        return name == "h" || self.find(name).is_some();
+    }
+
+    /**
+     * Find a command by its name or one of its aliases, without the backslash
+     */
+    pub fn find(&self, name: &str) -> Option<&Command> {
+        return self
+            .commands
+            .iter()
+            .find(|command| command.name == name || command.aliases.contains(&name));
+    }
+
+    /**
+     * The name and aliases of every command, \h included, without the backslash
+     */
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = vec!["h"];
+        for command in &self.commands {
+            names.push(command.name);
+            names.extend(command.aliases.iter());
+        }
+        return names;
+    }
+
+    /**
+     * Print every command's usage and help, or the help of a single command
+     */
+    pub fn print_help(&self, name: &str) {
+        if !name.is_empty() {
+            match self.find(name.trim_start_matches('\\')) {
+                Some(command) => {
+                    println!("{} - {}", command.usage(), command.help);
+                    if !command.aliases.is_empty() {
+                        let aliases: Vec<String> = command
+                            .aliases
+                            .iter()
+                            .map(|alias| format!("\\{}", alias))
+                            .collect();
+                        println!("Aliases: {}", aliases.join(", "));
+                    }
+                }
+                None => println!("Unknown command {}. Type \\h for help", name),
+            }
+            return;
+        }
+
+        println!("Special commands:");
+        println!("\\h [command] - Help");
+        for command in &self.commands {
+            println!("{} - {}", command.usage(), command.help);
+        }
+    }
+
+    /**
+     * Run a line starting with a backslash as a command. Unknown commands and usage errors are
+     * reported.
+     */
+    pub fn run(&self, chat: &mut Chat, input: &str) -> CommandResult {
+        let input = input.trim().trim_start_matches('\\');
+        let (name, text) = input.split_once(char::is_whitespace).unwrap_or((input, ""));
+        if name == "h" {
+            self.print_help(text.trim());
+            return CommandResult::Done;
+        }
+
+        let command = match self.find(name) {
+            Some(command) => command,
+            None => {
+                println!("Unknown command \\{}. Type \\h for help", name);
+                return CommandResult::Done;
+            }
+        };
+        match command.parse_args(text) {
+            Ok(args) => return (command.run)(chat, &args),
+            Err(e) => {
+                println!("Usage: {} ({})", command.usage(), e);
+                return CommandResult::Done;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> CommandRegistry {
+        let mut commands = CommandRegistry::new();
+        commands.register(Command {
+            name: "checkout",
+            aliases: &[],
+            args: &[ArgSpec::TurnId("N")],
+            help: "",
+            run: Box::new(|_, _| CommandResult::Done),
+        });
+        commands.register(Command {
+            name: "w",
+            aliases: &[],
+            args: &[ArgSpec::Number("N"), ArgSpec::OptionalText("file")],
+            help: "",
+            run: Box::new(|_, _| CommandResult::Done),
+        });
+        return commands;
+    }
+
+    #[test]
+    fn turn_ids_start_at_zero() {
+        let commands = registry();
+        let checkout = commands.find("checkout").unwrap();
+        assert_eq!(checkout.parse_args("0"), Ok(vec!["0".to_string()]));
+        assert_eq!(checkout.parse_args(" 12 "), Ok(vec!["12".to_string()]));
+        assert!(checkout.parse_args("").is_err());
+        assert!(checkout.parse_args("-1").is_err());
+        assert!(checkout.parse_args("0 1").is_err());
+    }
+
+    #[test]
+    fn code_block_numbers_start_at_one() {
+        let commands = registry();
+        let write = commands.find("w").unwrap();
+        assert!(write.parse_args("0").is_err());
+        assert_eq!(
+            write.parse_args("1 src/main.rs"),
+            Ok(vec!["1".to_string(), "src/main.rs".to_string()])
+        );
+        assert_eq!(
+            write.parse_args("2"),
+            Ok(vec!["2".to_string(), String::new()])
+        );
+    }
+}
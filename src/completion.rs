@@ -0,0 +1,136 @@
+use crate::chat::Chat;
+use crate::command_registry::CommandRegistry;
+use crate::config;
+use crate::llm::KNOWN_MODELS;
+use crate::syntax;
+use std::fs;
+use std::path::Path;
+
+/**
+ * The candidates for the word before the cursor
+ */
+pub struct Completion {
+    pub start: usize,            // chars of the line before the word being completed
+    pub candidates: Vec<String>, // replacements for the word, sorted
+}
+
+impl Completion {
+    pub fn none() -> Completion {
+        return Completion {
+            start: 0,
+            candidates: Vec::new(),
+        };
+    }
+}
+
+/**
+ * Files and directories whose path starts with `word`, directories ending with a slash. Hidden
+ * entries are left out unless `word` names one.
+ */
+fn complete_path(word: &str) -> Vec<String> {
+    let (dir, prefix) = match word.rfind('/') {
+        Some(i) => (&word[..i + 1], &word[i + 1..]),
+        None => ("", word),
+    };
+    let dir_path = match dir {
+        "" => Path::new("."),
+        _ => Path::new(dir),
+    };
+    let entries = match fs::read_dir(dir_path) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut candidates: Vec<String> = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+            continue;
+        }
+        // follows symlinks, so that a link to a directory completes like one
+        let is_dir = fs::metadata(entry.path())
+            .map(|meta| meta.is_dir())
+            .unwrap_or(false);
+        candidates.push(format!("{}{}{}", dir, name, if is_dir { "/" } else { "" }));
+    }
+    return candidates;
+}
+
+/**
+ * The models \model can switch to: the known ones, the configured one and the current one
+ */
+fn model_names(chat: &Chat) -> Vec<String> {
+    let mut names: Vec<String> = KNOWN_MODELS.iter().map(|name| name.to_string()).collect();
+    names.push(config::get().model.clone());
+    names.push(chat.model());
+    return names;
+}
+
+/**
+ * Complete the word before the cursor: command names after a backslash, then the arguments of the
+ * commands that take a file, a code block number, a model, a persona or a command.
+ *
+ * @param line: the line up to the cursor
+ */
+pub fn complete(commands: &CommandRegistry, chat: &Chat, line: &str) -> Completion {
+    if !line.starts_with('\\') {
+        return Completion::none();
+    }
+    let words: Vec<&str> = line.split_whitespace().collect();
+    // the word being completed, empty right after a space
+    let word = match line.ends_with(char::is_whitespace) {
+        true => "",
+        false => words.last().copied().unwrap_or(""),
+    };
+    let index = match word.is_empty() {
+        true => words.len(),
+        false => words.len() - 1,
+    };
+    let start = line.chars().count() - word.chars().count();
+
+    if index == 0 {
+        let mut candidates: Vec<String> = commands
+            .names()
+            .iter()
+            .map(|name| format!("\\{}", name))
+            .filter(|name| name.starts_with(word))
+            .collect();
+        candidates.sort();
+        return Completion { start, candidates };
+    }
+
+    let name = words[0].trim_start_matches('\\');
+    let command = match name {
+        "h" => "h",
+        _ => match commands.find(name) {
+            Some(command) => command.name,
+            None => return Completion::none(),
+        },
+    };
+    let mut candidates: Vec<String> = match (command, index) {
+        ("f", _) | ("w", 2) => complete_path(word),
+        ("y", 1) | ("w", 1) | ("o", 1) | ("run", 1) | ("apply", 1) => {
+            let count = chat.get_code_blocks().len();
+            let mut numbers: Vec<String> = (1..=count).map(|n| n.to_string()).collect();
+            if command == "y" {
+                numbers.push("all".to_string());
+            }
+            numbers
+        }
+        ("model", 1) => model_names(chat),
+        ("persona", 1) => config::get().personas.keys().cloned().collect(),
+        ("themes", 1) => syntax::theme_names(),
+        ("h", 1) => commands
+            .names()
+            .iter()
+            .map(|name| name.to_string())
+            .collect(),
+        _ => Vec::new(),
+    };
+    candidates.retain(|candidate| candidate.starts_with(word));
+    candidates.sort_by(|a, b| match (a.parse::<usize>(), b.parse::<usize>()) {
+        (Ok(a), Ok(b)) => a.cmp(&b),
+        _ => a.cmp(b),
+    });
+    candidates.dedup();
+    return Completion { start, candidates };
+}
@@ -0,0 +1,423 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use toml::{Table, Value};
+
+/**
+ * The defaults every setting falls back to, in the same format as the config file
+ */
+const DEFAULT_CONFIG: &str = r#"
+backend = "copilot"
+model = "gpt-4"
+temperature = 0.1
+top_p = 1.0
+theme = "auto"
+line_width = 80
+wrap_code = false
+hyperlinks = true
+
+[keybindings]
+mode = "emacs"
+
+[retry]
+attempts = 2
+backoff_ms = 1000
+
+[personas.reviewer]
+system = "You are a meticulous code reviewer. Point out bugs and risky changes first, then style."
+"#;
+
+const BACKENDS: [&str; 1] = ["copilot"];
+
+// environment variables overriding settings, between the config file and the command line flags
+const ENV_OVERRIDES: [(&str, &str); 8] = [
+    ("COPILOT_X_BACKEND", "backend"),
+    ("COPILOT_X_MODEL", "model"),
+    ("COPILOT_X_TEMPERATURE", "temperature"),
+    ("COPILOT_X_THEME", "theme"),
+    ("COPILOT_X_LINE_WIDTH", "line_width"),
+    ("COPILOT_X_WRAP_CODE", "wrap_code"),
+    ("COPILOT_X_HYPERLINKS", "hyperlinks"),
+    ("COPILOT_X_PERSONA", "persona"),
+];
+const PROFILE_ENV: &str = "COPILOT_X_PROFILE";
+// line_width that wraps prose at the terminal's width
+const FULL_WIDTH: &str = "full";
+
+/**
+ * A [run.<extension>] table, changing how code blocks of a language are run. Unset settings keep
+ * the built-in ones
+ */
+pub struct RunSettings {
+    pub command: Option<Vec<String>>, // the command, run in the scratch directory
+    pub file: Option<String>,         // the file the code block is written to
+    pub timeout_secs: Option<u64>,
+}
+
+pub struct Config {
+    pub model: String,
+    pub temperature: f64,
+    pub top_p: f64,
+    pub max_tokens: Option<i64>,
+    pub theme: String,
+    pub line_width: usize, // columns prose is wrapped at, usize::MAX for the terminal's full width
+    pub wrap_code: bool, // long code lines are wrapped with a marker, the terminal wraps them otherwise
+    pub hyperlinks: bool, // urls and file references are drawn as OSC 8 hyperlinks
+    pub vi_mode: bool,   // the line editor's keybindings, emacs otherwise
+    pub keybindings: BTreeMap<String, String>, // key(example: "ctrl-y") to the input it sends
+    pub retry_attempts: u32, // how many times a failed request is retried
+    pub retry_backoff_ms: u64, // wait before the first retry, doubled for each following one
+    pub persona: Option<String>, // system prompt every conversation starts with
+    pub personas: BTreeMap<String, String>, // persona name to system prompt
+    pub run: BTreeMap<String, RunSettings>, // language extension(example: "py") to how it's run
+    pub profile: Option<String>,
+    pub path: PathBuf, // the config file, which may not exist
+    pub merged: Table, // every effective setting, for `config show`
+}
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/**
+ * Make a loaded config the one returned by get()
+ */
+pub fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+/**
+ * The effective config, the defaults if init() wasn't called
+ */
+pub fn get() -> &'static Config {
+    return CONFIG.get_or_init(|| {
+        let defaults: Table = DEFAULT_CONFIG.parse().unwrap();
+        return from_table(defaults, None, config_path()).unwrap();
+    });
+}
+
+/**
+ * $XDG_CONFIG_HOME/copilot-x-cli/config.toml, with XDG_CONFIG_HOME defaulting to ~/.config
+ */
+pub fn config_path() -> PathBuf {
+    let mut path = match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let mut home = PathBuf::from(env::var("HOME").unwrap_or_default());
+            home.push(".config");
+            home
+        }
+    };
+    path.push("copilot-x-cli");
+    path.push("config.toml");
+    return path;
+}
+
+/**
+ * $XDG_DATA_HOME/copilot-x-cli, with XDG_DATA_HOME defaulting to ~/.local/share
+ */
+pub fn data_dir() -> Option<PathBuf> {
+    let mut path = match env::var("XDG_DATA_HOME") {
+        Ok(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let mut home = PathBuf::from(env::var("HOME").ok()?);
+            home.push(".local/share");
+            home
+        }
+    };
+    path.push("copilot-x-cli");
+    return Some(path);
+}
+
+/**
+ * Recursively merge `overlay` into `base`, tables are merged key by key and anything else is
+ * replaced
+ */
+fn merge(base: &mut Table, overlay: Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base_table)), Value::Table(overlay_table)) => {
+                merge(base_table, overlay_table);
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/**
+ * Parse a setting given as text(from a flag or an environment variable) into the type of its
+ * current value, so that "0.7" stays a number and "gpt-4" a string
+ */
+fn parse_like(current: Option<&Value>, text: &str) -> Result<Value, String> {
+    return match current {
+        Some(Value::Integer(_)) => match text.parse::<i64>() {
+            Ok(n) => Ok(Value::Integer(n)),
+            Err(_) if text == FULL_WIDTH => Ok(Value::String(text.to_string())),
+            Err(_) => Err(format!("expected a whole number, got {}", text)),
+        },
+        Some(Value::Float(_)) => match text.parse::<f64>() {
+            Ok(n) => Ok(Value::Float(n)),
+            Err(_) => Err(format!("expected a number, got {}", text)),
+        },
+        Some(Value::Boolean(_)) => match text.parse::<bool>() {
+            Ok(b) => Ok(Value::Boolean(b)),
+            Err(_) => Err(format!("expected true or false, got {}", text)),
+        },
+        // line_width = "full" may be overridden with a number
+        Some(Value::String(current)) if current == FULL_WIDTH => match text.parse::<i64>() {
+            Ok(n) => Ok(Value::Integer(n)),
+            Err(_) => Ok(Value::String(text.to_string())),
+        },
+        _ => Ok(Value::String(text.to_string())),
+    };
+}
+
+fn get_str(table: &Table, key: &str) -> Result<Option<String>, String> {
+    return match table.get(key) {
+        Some(Value::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(format!("{} must be a string", key)),
+        None => Ok(None),
+    };
+}
+
+fn get_float(table: &Table, key: &str) -> Result<f64, String> {
+    return match table.get(key) {
+        Some(Value::Float(n)) => Ok(*n),
+        Some(Value::Integer(n)) => Ok(*n as f64),
+        _ => Err(format!("{} must be a number", key)),
+    };
+}
+
+fn get_bool(table: &Table, key: &str) -> Result<bool, String> {
+    return match table.get(key) {
+        Some(Value::Boolean(b)) => Ok(*b),
+        _ => Err(format!("{} must be true or false", key)),
+    };
+}
+
+fn get_int(table: &Table, key: &str) -> Result<Option<i64>, String> {
+    return match table.get(key) {
+        Some(Value::Integer(n)) if *n >= 0 => Ok(Some(*n)),
+        Some(_) => Err(format!("{} must be a positive whole number", key)),
+        None => Ok(None),
+    };
+}
+
+fn get_table<'a>(table: &'a Table, key: &str) -> Result<Option<&'a Table>, String> {
+    return match table.get(key) {
+        Some(Value::Table(t)) => Ok(Some(t)),
+        Some(_) => Err(format!("{} must be a table", key)),
+        None => Ok(None),
+    };
+}
+
+fn get_str_array(table: &Table, key: &str) -> Result<Option<Vec<String>>, String> {
+    let error = format!("{} must be a list of strings", key);
+    return match table.get(key) {
+        Some(Value::Array(values)) => values
+            .iter()
+            .map(|value| value.as_str().map(|s| s.to_string()).ok_or(error.clone()))
+            .collect::<Result<Vec<String>, String>>()
+            .map(Some),
+        Some(_) => Err(error),
+        None => Ok(None),
+    };
+}
+
+/**
+ * Parse the [run.<extension>] tables
+ */
+fn get_run_settings(merged: &Table) -> Result<BTreeMap<String, RunSettings>, String> {
+    let mut run: BTreeMap<String, RunSettings> = BTreeMap::new();
+    if let Some(table) = get_table(merged, "run")? {
+        for extension in table.keys() {
+            let settings = match get_table(table, extension)? {
+                Some(settings) => settings,
+                None => continue,
+            };
+            let in_table = |e: String| format!("run.{}.{}", extension, e);
+            let command = get_str_array(settings, "command").map_err(in_table)?;
+            if command.as_ref().is_some_and(|command| command.is_empty()) {
+                return Err(in_table("command must not be empty".to_string()));
+            }
+            let timeout_secs = get_int(settings, "timeout_secs").map_err(in_table)?;
+            if timeout_secs == Some(0) {
+                return Err(in_table("timeout_secs must be at least 1".to_string()));
+            }
+            run.insert(
+                extension.clone(),
+                RunSettings {
+                    command,
+                    file: get_str(settings, "file").map_err(in_table)?,
+                    timeout_secs: timeout_secs.map(|secs| secs as u64),
+                },
+            );
+        }
+    }
+    return Ok(run);
+}
+
+/**
+ * Build the typed config out of the merged settings
+ */
+fn from_table(merged: Table, profile: Option<String>, path: PathBuf) -> Result<Config, String> {
+    let backend = get_str(&merged, "backend")?.unwrap_or_default();
+    if !BACKENDS.contains(&backend.as_str()) {
+        return Err(format!(
+            "unknown backend {}, expected one of: {}",
+            backend,
+            BACKENDS.join(", ")
+        ));
+    }
+
+    let mut keybindings: BTreeMap<String, String> = BTreeMap::new();
+    if let Some(table) = get_table(&merged, "keybindings")? {
+        for key in table.keys() {
+            let input = get_str(table, key)?.unwrap_or_default();
+            keybindings.insert(key.clone(), input);
+        }
+    }
+    let vi_mode = match keybindings.remove("mode").as_deref() {
+        Some("emacs") => false,
+        Some("vi") => true,
+        _ => return Err("keybindings.mode must be emacs or vi".to_string()),
+    };
+
+    let mut personas: BTreeMap<String, String> = BTreeMap::new();
+    if let Some(table) = get_table(&merged, "personas")? {
+        for (name, persona) in table {
+            let system = match persona {
+                Value::Table(persona) => get_str(persona, "system")?,
+                _ => None,
+            };
+            match system {
+                Some(system) => personas.insert(name.clone(), system),
+                None => return Err(format!("personas.{} needs a system prompt", name)),
+            };
+        }
+    }
+    let persona = match get_str(&merged, "persona")? {
+        Some(name) => match personas.get(&name) {
+            Some(system) => Some(system.clone()),
+            None => return Err(format!("persona {} is not defined in [personas]", name)),
+        },
+        None => None,
+    };
+
+    let retry = get_table(&merged, "retry")?.cloned().unwrap_or_default();
+    let line_width = match merged.get("line_width") {
+        Some(Value::Integer(n)) if *n >= 1 => *n as usize,
+        Some(Value::String(s)) if s == FULL_WIDTH => usize::MAX,
+        _ => {
+            return Err(format!(
+                "line_width must be at least 1, or \"{}\"",
+                FULL_WIDTH
+            ))
+        }
+    };
+
+    return Ok(Config {
+        model: get_str(&merged, "model")?.unwrap_or_default(),
+        temperature: get_float(&merged, "temperature")?,
+        top_p: get_float(&merged, "top_p")?,
+        max_tokens: get_int(&merged, "max_tokens")?,
+        theme: get_str(&merged, "theme")?.unwrap_or_default(),
+        line_width,
+        wrap_code: get_bool(&merged, "wrap_code")?,
+        hyperlinks: get_bool(&merged, "hyperlinks")?,
+        vi_mode,
+        keybindings,
+        retry_attempts: get_int(&retry, "attempts")?.unwrap_or(0) as u32,
+        retry_backoff_ms: get_int(&retry, "backoff_ms")?.unwrap_or(0) as u64,
+        persona,
+        personas,
+        run: get_run_settings(&merged)?,
+        profile,
+        path,
+        merged,
+    });
+}
+
+/**
+ * Load the effective config. Settings are looked up in this order, the first one wins:
+ * command line flags, COPILOT_X_* environment variables, the selected profile, the rest of the
+ * config file, and the defaults.
+ *
+ * @param profile: the profile given on the command line, COPILOT_X_PROFILE is used otherwise
+ * @param flags: settings given on the command line, as (setting, value) pairs
+ */
+pub fn load(profile: Option<&str>, flags: &[(&str, &str)]) -> Result<Config, String> {
+    let mut merged: Table = DEFAULT_CONFIG.parse().unwrap();
+    let path = config_path();
+
+    let mut file: Table = match fs::read_to_string(&path) {
+        Ok(content) => match content.parse() {
+            Ok(file) => file,
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        },
+        Err(_) => Table::new(),
+    };
+    let mut profiles = match file.remove("profiles") {
+        Some(Value::Table(profiles)) => profiles,
+        Some(_) => return Err("profiles must be a table of tables".to_string()),
+        None => Table::new(),
+    };
+    merge(&mut merged, file);
+
+    let profile = match profile {
+        Some(profile) => Some(profile.to_string()),
+        None => env::var(PROFILE_ENV)
+            .ok()
+            .filter(|profile| !profile.is_empty()),
+    };
+    if let Some(name) = &profile {
+        match profiles.remove(name) {
+            Some(Value::Table(settings)) => merge(&mut merged, settings),
+            Some(_) => return Err(format!("profiles.{} must be a table", name)),
+            None => {
+                let mut names: Vec<&String> = profiles.keys().collect();
+                names.sort();
+                let names: Vec<&str> = names.iter().map(|name| name.as_str()).collect();
+                return Err(format!(
+                    "no profile named {} in {}, defined profiles: {}",
+                    name,
+                    path.display(),
+                    names.join(", ")
+                ));
+            }
+        }
+    }
+
+    for (var, key) in ENV_OVERRIDES {
+        if let Ok(text) = env::var(var) {
+            let value =
+                parse_like(merged.get(key), &text).map_err(|e| format!("{}: {}", var, e))?;
+            merged.insert(key.to_string(), value);
+        }
+    }
+    for (key, text) in flags {
+        let value = parse_like(merged.get(*key), text).map_err(|e| format!("--{}: {}", key, e))?;
+        merged.insert(key.to_string(), value);
+    }
+
+    let path_name = path.display().to_string();
+    return from_table(merged, profile, path).map_err(|e| format!("{}: {}", path_name, e));
+}
+
+/**
+ * Print the effective config in the config file's format
+ */
+pub fn print_config(config: &Config) {
+    let source = if config.path.exists() {
+        config.path.display().to_string()
+    } else {
+        format!("{} (not found, using defaults)", config.path.display())
+    };
+    println!("# config file: {}", source);
+    if let Some(profile) = &config.profile {
+        println!("# profile: {}", profile);
+    }
+    print!("{}", config.merged);
+}
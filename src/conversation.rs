@@ -0,0 +1,191 @@
+use crate::llm::{LLMMessage, LLMRole};
+use serde_json::Value;
+
+/**
+ * A single message in the conversation tree. Turns are identified by their index in the tree,
+ * which is stable for the lifetime of the session(only the last turn added may be removed, see
+ * `pop`).
+ */
+pub struct Turn {
+    pub message: LLMMessage,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+}
+
+/**
+ * Conversation stored as a tree of turns. The path from a root to `head` is the history that gets
+ * sent to the model. Asking a new question appends to `head`; editing an earlier question forks a
+ * new branch from that question's parent, leaving the old branch intact.
+ */
+pub struct ConversationTree {
+    turns: Vec<Turn>,
+    head: Option<usize>, // the turn new messages are appended to, None for an empty conversation
+}
+
+impl ConversationTree {
+    pub fn new() -> ConversationTree {
+        ConversationTree {
+            turns: Vec::new(),
+            head: None,
+        }
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Turn> {
+        return self.turns.get(id);
+    }
+
+    /**
+     * Append a message after the current head, and move head to the new turn
+     * returns the id of the new turn
+     */
+    pub fn push(&mut self, message: LLMMessage) -> usize {
+        let id = self.turns.len();
+        self.turns.push(Turn {
+            message,
+            parent: self.head,
+            children: Vec::new(),
+        });
+        if let Some(parent) = self.head {
+            self.turns[parent].children.push(id);
+        }
+        self.head = Some(id);
+        return id;
+    }
+
+    /**
+     * Remove the head turn and move head back to its parent, undoing the last `push`(example: a
+     * question whose request failed). Only the last turn added can be removed, so that the ids of
+     * the other turns stay stable.
+     * returns the removed message, None if the head isn't the last turn added or has children
+     */
+    pub fn pop(&mut self) -> Option<LLMMessage> {
+        let id = self.head?;
+        if id + 1 != self.turns.len() || !self.turns[id].children.is_empty() {
+            return None;
+        }
+        let turn = self.turns.pop().unwrap();
+        if let Some(parent) = turn.parent {
+            self.turns[parent].children.retain(|child| *child != id);
+        }
+        self.head = turn.parent;
+        return Some(turn.message);
+    }
+
+    /**
+     * Ids of the turns from the root to the head, in order
+     */
+    pub fn path(&self) -> Vec<usize> {
+        let mut path = Vec::new();
+        let mut curr = self.head;
+        while let Some(id) = curr {
+            path.push(id);
+            curr = self.turns[id].parent;
+        }
+        path.reverse();
+        return path;
+    }
+
+    /**
+     * The messages on the current path, which is what the model sees
+     */
+    pub fn history(&self) -> Vec<LLMMessage> {
+        return self
+            .path()
+            .iter()
+            .map(|id| self.turns[*id].message.clone())
+            .collect();
+    }
+
+    /**
+     * Move head to the given turn. returns false if the turn does not exist
+     */
+    pub fn checkout(&mut self, id: usize) -> bool {
+        if id >= self.turns.len() {
+            return false;
+        }
+        self.head = Some(id);
+        return true;
+    }
+
+    /**
+     * Move head to the parent of the given user turn, so that the next question asked becomes a
+     * sibling of it. returns false if the turn does not exist or is not a user question
+     */
+    pub fn fork(&mut self, id: usize) -> bool {
+        match self.turns.get(id) {
+            Some(turn) => match turn.message.owner {
+                LLMRole::User => {
+                    self.head = turn.parent;
+                    return true;
+                }
+                _ => return false,
+            },
+            None => return false,
+        }
+    }
+
+    /**
+     * Ids of all turns without children, each one is the tip of a branch
+     */
+    pub fn leaves(&self) -> Vec<usize> {
+        return (0..self.turns.len())
+            .filter(|id| self.turns[*id].children.is_empty())
+            .collect();
+    }
+
+    /**
+     * Serialize the tree into the session format:
+     * { "head": 3, "turns": [{ "parent": null, "role": "user", "content": "..." }, ...] }
+     */
+    pub fn to_json(&self) -> Value {
+        let turns: Vec<Value> = self
+            .turns
+            .iter()
+            .map(|turn| {
+                serde_json::json!({
+                    "parent": turn.parent,
+                    "role": turn.message.owner.as_str(),
+                    "content": turn.message.content,
+                })
+            })
+            .collect();
+        return serde_json::json!({
+            "head": self.head,
+            "turns": turns,
+        });
+    }
+
+    /**
+     * Deserialize a tree from the session format, returns None if the json is malformed
+     */
+    pub fn from_json(json: &Value) -> Option<ConversationTree> {
+        let mut tree = ConversationTree::new();
+        for turn in json["turns"].as_array()? {
+            let parent = match &turn["parent"] {
+                Value::Null => None,
+                parent => Some(parent.as_u64()? as usize),
+            };
+            // parents always come before their children
+            if let Some(parent) = parent {
+                if parent >= tree.turns.len() {
+                    return None;
+                }
+            }
+            tree.head = parent;
+            tree.push(LLMMessage {
+                owner: LLMRole::from_name(turn["role"].as_str()?)?,
+                content: turn["content"].as_str()?.to_string(),
+            });
+        }
+        tree.head = match &json["head"] {
+            Value::Null => None,
+            head => Some(head.as_u64()? as usize),
+        };
+        if let Some(head) = tree.head {
+            if head >= tree.turns.len() {
+                return None;
+            }
+        }
+        return Some(tree);
+    }
+}
@@ -0,0 +1,26 @@
+use similar::{ChangeTag, TextDiff};
+
+/**
+ * Print a colored unified diff between two versions of a file
+ *
+ * @param path: the file's path, used for the diff header
+ * @param old: the current content, empty for a new file
+ * @param new: the content about to be written
+ */
+pub fn print_diff(path: &str, old: &str, new: &str) {
+    let diff = TextDiff::from_lines(old, new);
+    println!("\x1b[1m--- a/{}\x1b[0m", path);
+    println!("\x1b[1m+++ b/{}\x1b[0m", path);
+    for hunk in diff.unified_diff().context_radius(3).iter_hunks() {
+        println!("\x1b[36m{}\x1b[0m", hunk.header());
+        for change in hunk.iter_changes() {
+            let (sign, color) = match change.tag() {
+                ChangeTag::Delete => ("-", "\x1b[31m"),
+                ChangeTag::Insert => ("+", "\x1b[32m"),
+                ChangeTag::Equal => (" ", ""),
+            };
+            let line = change.value().trim_end_matches('\n');
+            println!("{}{}{}\x1b[0m", color, sign, line);
+        }
+    }
+}
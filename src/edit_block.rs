@@ -0,0 +1,220 @@
+use crate::codeblock_builder::find_file_path;
+use crate::file_ops::{self, FileChange};
+use crate::patch;
+use std::fs;
+use std::path::Path;
+
+/**
+ * System prompt teaching the model to answer code changes with search/replace edit blocks
+ */
+pub const EDIT_BLOCK_SYSTEM_PROMPT: &str = "When you change existing code, answer with SEARCH/REPLACE \
+edit blocks instead of full files or diffs. Each edit block starts with the path of the file on its \
+own line, followed by:
+<<<<<<< SEARCH
+the exact lines to find in the file, including indentation and enough context to be unique
+=======
+the lines to replace them with
+>>>>>>> REPLACE
+Use one edit block per change, several edit blocks may target the same file. To create a new file, \
+leave the SEARCH section empty. Put the edit blocks in a fenced code block.";
+
+const SEARCH_MARKER: &str = "<<<<<<< SEARCH";
+const DIVIDER_MARKER: &str = "=======";
+const REPLACE_MARKER: &str = ">>>>>>> REPLACE";
+
+pub struct EditBlock {
+    pub path: String,
+    pub search: Vec<String>,
+    pub replace: Vec<String>,
+}
+
+/**
+ * Parse every SEARCH/REPLACE edit block out of a response. The file an edit block applies to is
+ * the closest non-empty line before it, fence lines are skipped over.
+ */
+pub fn parse_edit_blocks(text: &str) -> Result<Vec<EditBlock>, String> {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut edit_blocks: Vec<EditBlock> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        if lines[i].trim() != SEARCH_MARKER {
+            i += 1;
+            continue;
+        }
+
+        let previous_line = lines[..i]
+            .iter()
+            .rev()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty())
+            .find(|line| !(line.starts_with("```") && line.trim_matches('`').is_empty()));
+        let path = match previous_line {
+            // consecutive edit blocks for the same file only name it once
+            Some(REPLACE_MARKER) => edit_blocks.last().map(|edit| edit.path.clone()),
+            Some(line) => {
+                // the file name may share the line with the opening fence, ```rust src/main.rs
+                let line = line.trim_start_matches('`');
+                find_file_path(line).or_else(|| {
+                    if line.contains(' ') {
+                        None
+                    } else {
+                        Some(line.to_string())
+                    }
+                })
+            }
+            None => None,
+        };
+        let path = match path {
+            Some(path) => path,
+            None => {
+                return Err(format!(
+                    "no file name before the edit block on line {}",
+                    i + 1
+                ))
+            }
+        };
+
+        let mut search: Vec<String> = Vec::new();
+        let mut replace: Vec<String> = Vec::new();
+        let mut in_replace = false;
+        let mut terminated = false;
+        i += 1;
+        while i < lines.len() {
+            let line = lines[i];
+            if line.trim() == DIVIDER_MARKER && !in_replace {
+                in_replace = true;
+            } else if line.trim() == REPLACE_MARKER {
+                terminated = true;
+                break;
+            } else if in_replace {
+                replace.push(line.to_string());
+            } else {
+                search.push(line.to_string());
+            }
+            i += 1;
+        }
+        if !terminated {
+            return Err(format!("the edit block for {} is not terminated", path));
+        }
+        edit_blocks.push(EditBlock {
+            path,
+            search,
+            replace,
+        });
+        i += 1;
+    }
+    return Ok(edit_blocks);
+}
+
+/**
+ * Apply the edit blocks targeting one file to its content. An empty SEARCH section adds its lines
+ * to a new file, and isn't allowed for an existing one.
+ *
+ * @param content: the file's content, None if the file doesn't exist
+ * returns the new content, or why an edit block didn't match
+ */
+fn apply_to_content(content: Option<&str>, edit_blocks: &[&EditBlock]) -> Result<String, String> {
+    let mut lines: Vec<String> = content
+        .unwrap_or("")
+        .lines()
+        .map(|line| line.to_string())
+        .collect();
+    for (i, edit_block) in edit_blocks.iter().enumerate() {
+        if edit_block.search.is_empty() {
+            if content.is_some() {
+                return Err(format!(
+                    "edit block #{} has an empty SEARCH section, but the file already exists",
+                    i + 1
+                ));
+            }
+            lines.extend(edit_block.replace.iter().cloned());
+            continue;
+        }
+        let search: Vec<&str> = edit_block.search.iter().map(|line| line.as_str()).collect();
+        match patch::find_lines(&lines, &search, 0, 0) {
+            Some(pos) => {
+                lines.splice(pos..pos + search.len(), edit_block.replace.iter().cloned());
+            }
+            None => {
+                return Err(format!(
+                    "edit block #{} does not match the file: {}",
+                    i + 1,
+                    edit_block
+                        .search
+                        .first()
+                        .map(|line| line.trim())
+                        .unwrap_or("")
+                ));
+            }
+        }
+    }
+    return Ok(patch::join_lines(&lines, content.unwrap_or("")));
+}
+
+/**
+ * Apply every edit block in a response. The edits are previewed and written after confirmation,
+ * if any edit block doesn't match nothing is written.
+ *
+ * returns true if the edits were applied
+ */
+pub fn apply_edit_blocks(text: &str) -> bool {
+    let edit_blocks = match parse_edit_blocks(text) {
+        Ok(edit_blocks) => edit_blocks,
+        Err(e) => {
+            println!("Error: Malformed edit block: {}", e);
+            return false;
+        }
+    };
+    if edit_blocks.is_empty() {
+        println!("No SEARCH/REPLACE edit blocks found");
+        return false;
+    }
+
+    // group the edit blocks by file, keeping the order files first appear in
+    let mut paths: Vec<&str> = Vec::new();
+    for edit_block in &edit_blocks {
+        if !paths.contains(&edit_block.path.as_str()) {
+            paths.push(&edit_block.path);
+        }
+    }
+
+    let mut changes: Vec<FileChange> = Vec::new();
+    let mut rejections: Vec<String> = Vec::new();
+    for path in paths {
+        let file_edit_blocks: Vec<&EditBlock> = edit_blocks
+            .iter()
+            .filter(|edit| edit.path == path)
+            .collect();
+        let old = if Path::new(path).exists() {
+            match fs::read_to_string(path) {
+                Ok(content) => Some(content),
+                Err(e) => {
+                    rejections.push(format!("{}: could not read the file: {}", path, e));
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        match apply_to_content(old.as_deref(), &file_edit_blocks) {
+            Ok(new) => changes.push(FileChange {
+                path: path.to_string(),
+                old,
+                new: Some(new),
+            }),
+            Err(reason) => rejections.push(format!("{}: {}", path, reason)),
+        }
+    }
+
+    if !rejections.is_empty() {
+        println!("The edits do not apply, nothing was changed:");
+        for rejection in rejections {
+            println!("  {}", rejection);
+        }
+        return false;
+    }
+    if !file_ops::confirm_paths_outside_current_dir(&changes) {
+        return false;
+    }
+    return file_ops::apply_changes(&changes);
+}
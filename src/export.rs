@@ -0,0 +1,219 @@
+use crate::codeblock_builder::get_language_extension;
+use crate::llm::{LLMMessage, LLMRole};
+use crate::syntax;
+use std::path::Path;
+
+pub enum ExportFormat {
+    Markdown,
+    Html,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Option<ExportFormat> {
+        return match name {
+            "md" | "markdown" => Some(ExportFormat::Markdown),
+            "html" | "htm" => Some(ExportFormat::Html),
+            "json" => Some(ExportFormat::Json),
+            _ => None,
+        };
+    }
+
+    /**
+     * Guess the format from a file name's extension
+     */
+    pub fn from_path(path: &str) -> Option<ExportFormat> {
+        let extension = Path::new(path).extension()?.to_str()?;
+        return ExportFormat::from_name(extension);
+    }
+}
+
+/**
+ * A piece of a message, either prose or a fenced code block
+ */
+enum Segment {
+    Prose(String),
+    Code { language: String, code: String },
+}
+
+/**
+ * Split a message into prose and fenced code blocks. Fences may be indented, the language is the
+ * first word of the fence's info string. A code block is only closed by a fence at least as long as
+ * the one opening it, so that a ```` block can show a ``` block. An unterminated code block runs
+ * until the end of the message.
+ */
+fn split_segments(content: &str) -> Vec<Segment> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut prose = String::new();
+    let mut code = String::new();
+    let mut language = String::new();
+    let mut fence_len = 0; // backticks of the fence opening the code block, 0 outside of one
+
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if fence_len > 0 {
+            if trimmed.len() >= fence_len && trimmed.chars().all(|ch| ch == '`') {
+                segments.push(Segment::Code {
+                    language: language.clone(),
+                    code: code.clone(),
+                });
+                code.clear();
+                fence_len = 0;
+            } else {
+                code.push_str(line);
+                code.push('\n');
+            }
+        } else if trimmed.starts_with("```") {
+            if !prose.trim().is_empty() {
+                segments.push(Segment::Prose(prose.trim_end().to_string()));
+            }
+            prose.clear();
+            let info = trimmed.trim_start_matches('`');
+            fence_len = trimmed.len() - info.len();
+            language = info.split_whitespace().next().unwrap_or("").to_string();
+        } else {
+            prose.push_str(line);
+            prose.push('\n');
+        }
+    }
+
+    if fence_len > 0 {
+        segments.push(Segment::Code { language, code });
+    } else if !prose.trim().is_empty() {
+        segments.push(Segment::Prose(prose.trim_end().to_string()));
+    }
+    return segments;
+}
+
+fn role_title(role: &LLMRole) -> &'static str {
+    return match role {
+        LLMRole::User => "User",
+        LLMRole::Assistant => "Assistant",
+        LLMRole::System => "System",
+    };
+}
+
+fn escape_html(text: &str) -> String {
+    return text
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+}
+
+/**
+ * Render the conversation as markdown, re-fencing every code block so that it carries its
+ * language tag and its fence is longer than any run of backticks inside the code
+ */
+fn to_markdown(title: &str, history: &[LLMMessage]) -> String {
+    let mut out = format!("# {}\n", title);
+    for message in history {
+        out.push_str(&format!("\n## {}\n\n", role_title(&message.owner)));
+        for segment in split_segments(&message.content) {
+            match segment {
+                Segment::Prose(text) => {
+                    out.push_str(&text);
+                    out.push_str("\n\n");
+                }
+                Segment::Code { language, code } => {
+                    let mut longest_run = 0;
+                    let mut run = 0;
+                    for ch in code.chars() {
+                        run = if ch == '`' { run + 1 } else { 0 };
+                        longest_run = std::cmp::max(longest_run, run);
+                    }
+                    let fence = "`".repeat(std::cmp::max(3, longest_run + 1));
+                    out.push_str(&format!("{}{}\n{}{}\n\n", fence, language, code, fence));
+                }
+            }
+        }
+    }
+    return out.trim_end().to_string() + "\n";
+}
+
+/**
+ * Render the conversation as a standalone html page, code blocks are syntax highlighted with the
+ * same theme as the terminal output
+ */
+fn to_html(title: &str, history: &[LLMMessage]) -> String {
+    let mut body = String::new();
+    for message in history {
+        body.push_str(&format!(
+            "<section class=\"{}\">\n<h2>{}</h2>\n",
+            message.owner.as_str(),
+            role_title(&message.owner)
+        ));
+        for segment in split_segments(&message.content) {
+            match segment {
+                Segment::Prose(text) => {
+                    for paragraph in text.split("\n\n") {
+                        body.push_str(&format!(
+                            "<p>{}</p>\n",
+                            escape_html(paragraph.trim()).replace('\n', "<br>\n")
+                        ));
+                    }
+                }
+                Segment::Code { language, code } => {
+                    body.push_str(&syntax::get_syntax_highlighted_html(
+                        &code,
+                        &get_language_extension(&language),
+                    ));
+                }
+            }
+        }
+        body.push_str("</section>\n");
+    }
+
+    return format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>{title}</title>
+<style>
+body {{ max-width: 50em; margin: 2em auto; padding: 0 1em; font-family: sans-serif; line-height: 1.5; }}
+section {{ border-bottom: 1px solid #ddd; padding-bottom: 1em; }}
+section.user h2 {{ color: #2a6db0; }}
+section.assistant h2 {{ color: #3a8a3a; }}
+pre {{ padding: 1em; overflow-x: auto; border-radius: 4px; }}
+</style>
+</head>
+<body>
+<h1>{title}</h1>
+{body}</body>
+</html>
+"#,
+        title = escape_html(title),
+        body = body
+    );
+}
+
+/**
+ * Render the conversation as json, in the same shape as the messages sent to the API
+ */
+fn to_json(history: &[LLMMessage]) -> String {
+    let messages: Vec<serde_json::Value> = history
+        .iter()
+        .map(|message| {
+            serde_json::json!({
+                "role": message.owner.as_str(),
+                "content": message.content,
+            })
+        })
+        .collect();
+    return serde_json::to_string_pretty(&serde_json::json!({ "messages": messages })).unwrap();
+}
+
+/**
+ * Render a conversation in the given format
+ *
+ * @param title: the title of the document, ignored for json
+ * @param history: the messages to export, in order
+ */
+pub fn export(title: &str, history: &[LLMMessage], format: &ExportFormat) -> String {
+    return match format {
+        ExportFormat::Markdown => to_markdown(title, history),
+        ExportFormat::Html => to_html(title, history),
+        ExportFormat::Json => to_json(history),
+    };
+}
@@ -0,0 +1,250 @@
+use crate::codeblock_builder::CodeBlock;
+use crate::diff;
+use crate::prompt;
+use std::env;
+use std::fs;
+use std::path::{Component, Path};
+use std::process::Command;
+
+/**
+ * Whether a path stays in the current directory, once its `..` and the symlinks among the parts
+ * that exist are resolved
+ */
+fn is_inside_current_dir(path: &Path) -> bool {
+    let current_dir = match env::current_dir().and_then(|dir| dir.canonicalize()) {
+        Ok(dir) => dir,
+        Err(_) => return false,
+    };
+    let mut resolved = current_dir.clone();
+    for component in path.components() {
+        match component {
+            // an absolute path replaces the current directory
+            Component::Prefix(_) | Component::RootDir => resolved.push(component.as_os_str()),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(name) => {
+                resolved.push(name);
+                if let Ok(real) = resolved.canonicalize() {
+                    resolved = real;
+                }
+            }
+        }
+    }
+    return resolved.starts_with(&current_dir);
+}
+
+/**
+ * Confirm, file by file, the changes to paths outside of the current directory. The paths of
+ * diffs and edit blocks come from the model, like the path of a code block.
+ *
+ * returns false if the user refused one of them
+ */
+pub fn confirm_paths_outside_current_dir(changes: &[FileChange]) -> bool {
+    for change in changes {
+        if !is_inside_current_dir(Path::new(&change.path))
+            && !prompt::confirm(&format!(
+                "{} is outside of the current directory, change it anyway?",
+                change.path
+            ))
+        {
+            println!("Nothing was changed");
+            return false;
+        }
+    }
+    return true;
+}
+
+/**
+ * Write a code block to a file. The path may come from the model, so the user has to confirm the
+ * write: creating the file, or overwriting it after seeing a diff against its current content.
+ * Paths outside of the current directory need one more confirmation.
+ *
+ * @param code_block: the code block to write
+ * @param path: where to write it, None to use the path the code block names
+ * returns true if the file was written
+ */
+pub fn write_code_block(code_block: &CodeBlock, path: Option<&str>) -> bool {
+    let path = match path.or(code_block.path.as_deref()) {
+        Some(path) => path,
+        None => {
+            println!("The code block doesn't name a file, please provide a path");
+            return false;
+        }
+    };
+
+    if !is_inside_current_dir(Path::new(path))
+        && !prompt::confirm(&format!(
+            "{} is outside of the current directory, write it anyway?",
+            path
+        ))
+    {
+        return false;
+    }
+
+    if Path::new(path).exists() {
+        let old = match fs::read_to_string(path) {
+            Ok(old) => old,
+            Err(e) => {
+                println!("Error: Could not read {}: {}", path, e);
+                return false;
+            }
+        };
+        if old == code_block.code {
+            println!("{} is already up to date", path);
+            return false;
+        }
+        diff::print_diff(path, &old, &code_block.code);
+        if !prompt::confirm(&format!("Overwrite {}?", path)) {
+            return false;
+        }
+    } else if !prompt::confirm(&format!(
+        "Create {} ({} lines)?",
+        path,
+        code_block.code.lines().count()
+    )) {
+        return false;
+    }
+
+    if let Some(parent) = Path::new(path).parent() {
+        if !parent.as_os_str().is_empty() {
+            if let Err(e) = fs::create_dir_all(parent) {
+                println!("Error: Could not create {}: {}", parent.display(), e);
+                return false;
+            }
+        }
+    }
+    match fs::write(path, &code_block.code) {
+        Ok(_) => {
+            println!(
+                "Wrote {} lines to {}",
+                code_block.code.lines().count(),
+                path
+            );
+            return true;
+        }
+        Err(e) => {
+            println!("Error: Could not write {}: {}", path, e);
+            return false;
+        }
+    }
+}
+
+/**
+ * Open a code block in the user's editor($VISUAL, $EDITOR, or vi) through a temp file named after
+ * the code block's language, so that the editor picks the right syntax
+ *
+ * @param label: the code block's number, used in the temp file's name
+ * returns the edited code if it was changed
+ */
+pub fn edit_code_block(code_block: &CodeBlock, label: usize) -> Option<String> {
+    let mut path = env::temp_dir();
+    path.push(format!(
+        "copilot-block-{}-{}.{}",
+        std::process::id(),
+        label,
+        code_block.language_extension
+    ));
+    if let Err(e) = fs::write(&path, &code_block.code) {
+        println!("Error: Could not write {}: {}", path.display(), e);
+        return None;
+    }
+
+    let editor = env::var("VISUAL")
+        .or(env::var("EDITOR"))
+        .unwrap_or("vi".to_string());
+    // the editor may come with arguments, such as "code --wait"
+    let mut editor_args = editor.split_whitespace();
+    let status = Command::new(editor_args.next().unwrap_or("vi"))
+        .args(editor_args)
+        .arg(&path)
+        .status();
+
+    let edited = match status {
+        Ok(status) if status.success() => fs::read_to_string(&path).ok(),
+        Ok(_) => {
+            println!("The editor exited with an error, discarding the changes");
+            None
+        }
+        Err(e) => {
+            println!("Error: Could not start {}: {}", editor, e);
+            None
+        }
+    };
+    let _ = fs::remove_file(&path);
+
+    return edited.filter(|edited| *edited != code_block.code);
+}
+
+/**
+ * A change to a single file, fully computed before anything is written
+ */
+pub struct FileChange {
+    pub path: String,
+    pub old: Option<String>, // None if the file is created
+    pub new: Option<String>, // None if the file is deleted
+}
+
+fn write_change(path: &str, content: &Option<String>) -> std::io::Result<()> {
+    match content {
+        Some(content) => {
+            if let Some(parent) = Path::new(path).parent() {
+                if !parent.as_os_str().is_empty() {
+                    fs::create_dir_all(parent)?;
+                }
+            }
+            // write next to the file and rename, so that a file is never half written. The rename
+            // replaces the file, which has to keep its permissions(example: an executable script)
+            let tmp_path = format!("{}.copilot-tmp", path);
+            fs::write(&tmp_path, content)?;
+            if let Ok(metadata) = fs::metadata(path) {
+                if let Err(e) = fs::set_permissions(&tmp_path, metadata.permissions()) {
+                    let _ = fs::remove_file(&tmp_path);
+                    return Err(e);
+                }
+            }
+            return fs::rename(&tmp_path, path);
+        }
+        None => return fs::remove_file(path),
+    }
+}
+
+/**
+ * Write all changes, or none of them: if a write fails, the files already written are restored
+ */
+fn write_changes(changes: &[FileChange]) -> bool {
+    for (i, change) in changes.iter().enumerate() {
+        if let Err(e) = write_change(&change.path, &change.new) {
+            println!("Error: Could not write {}: {}", change.path, e);
+            for written in changes[..i].iter().rev() {
+                if let Err(e) = write_change(&written.path, &written.old) {
+                    println!("Error: Could not restore {}: {}", written.path, e);
+                }
+            }
+            return false;
+        }
+    }
+    return true;
+}
+
+/**
+ * Preview a set of changes as diffs and write them after confirmation, all or nothing
+ * returns true if the changes were written
+ */
+pub fn apply_changes(changes: &[FileChange]) -> bool {
+    for change in changes {
+        match &change.new {
+            Some(new) => diff::print_diff(&change.path, change.old.as_deref().unwrap_or(""), new),
+            None => println!("\x1b[31mdelete {}\x1b[0m", change.path),
+        }
+    }
+    if !prompt::confirm(&format!("Apply changes to {} file(s)?", changes.len())) {
+        return false;
+    }
+    if !write_changes(changes) {
+        return false;
+    }
+    println!("Applied changes to {} file(s)", changes.len());
+    return true;
+}
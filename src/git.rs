@@ -0,0 +1,109 @@
+use std::process::Command;
+
+pub const REVIEW_PROMPT: &str = "Review the following staged changes. Point out bugs, risky \
+changes and missing edge cases first, then style issues. Refer to files and lines, and keep it short.";
+
+pub const COMMIT_MESSAGE_PROMPT: &str = "Write a git commit message for the following staged \
+changes. Use a short imperative subject line under 72 characters, then a blank line and a body \
+explaining what changed and why, wrapped at 72 characters. Answer with the commit message only, \
+without code fences or any other text.";
+
+/**
+ * Run git with the given arguments in the current directory
+ * returns git's stdout, or its error message
+ */
+fn run_git(args: &[&str]) -> Result<String, String> {
+    let output = match Command::new("git").args(args).output() {
+        Ok(output) => output,
+        Err(e) => return Err(format!("could not run git: {}", e)),
+    };
+    if !output.status.success() {
+        return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+    }
+    return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+}
+
+/**
+ * Wrap git output in a fenced code block, with a line saying what it is
+ */
+fn as_context(description: &str, language: &str, output: &str) -> String {
+    return format!(
+        "{}:\n```{}\n{}\n```",
+        description,
+        language,
+        output.trim_end()
+    );
+}
+
+/**
+ * The staged changes, formatted as context for a question
+ */
+pub fn staged_diff() -> Result<String, String> {
+    let diff = run_git(&["diff", "--cached"])?;
+    if diff.trim().is_empty() {
+        return Err("there are no staged changes".to_string());
+    }
+    return Ok(as_context("Staged changes", "diff", &diff));
+}
+
+/**
+ * The changes between two revisions(example: "main..HEAD"), formatted as context for a question
+ */
+pub fn range_diff(range: &str) -> Result<String, String> {
+    let diff = run_git(&["diff", range])?;
+    if diff.trim().is_empty() {
+        return Err(format!("there are no changes in {}", range));
+    }
+    return Ok(as_context(&format!("Changes in {}", range), "diff", &diff));
+}
+
+/**
+ * Blame for the lines around a line of a file, and the file's recent history, formatted as context
+ * for a question
+ *
+ * @param location: the path and the 1-based line(example: "src/chat.rs:42")
+ */
+pub fn blame(location: &str) -> Result<String, String> {
+    let (path, line) = match location.rsplit_once(':') {
+        Some((path, line)) => match line.parse::<usize>() {
+            Ok(line) if line > 0 => (path, line),
+            _ => return Err(format!("{} is not a line number", line)),
+        },
+        None => return Err("expected path:line".to_string()),
+    };
+
+    let range = format!("{},{}", line.saturating_sub(5).max(1), line + 5);
+    let blame = run_git(&["blame", "--date=short", "-L", &range, "--", path])?;
+    let history = run_git(&[
+        "log",
+        "-n",
+        "10",
+        "--date=short",
+        "--format=%h %ad %an %s",
+        "--",
+        path,
+    ])?;
+
+    return Ok(format!(
+        "{}\n\n{}",
+        as_context(
+            &format!("git blame of {} around line {}", path, line),
+            "",
+            &blame
+        ),
+        as_context(&format!("Recent commits touching {}", path), "", &history)
+    ));
+}
+
+/**
+ * Strip the code fence models like to put around a commit message despite being asked not to
+ */
+pub fn strip_code_fence(text: &str) -> String {
+    let text = text.trim();
+    let mut lines: Vec<&str> = text.lines().collect();
+    if lines.len() >= 2 && lines[0].starts_with("```") && lines[lines.len() - 1].trim() == "```" {
+        lines.remove(0);
+        lines.pop();
+    }
+    return lines.join("\n").trim().to_string();
+}
@@ -0,0 +1,961 @@
+use crate::completion::Completion;
+use crate::config;
+use crate::terminal;
+use crate::width;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::{clear, cursor};
+use unicode_segmentation::UnicodeSegmentation;
+
+const HISTORY_LIMIT: usize = 1000;
+const CONTINUATION_PROMPT: &str = ".. ";
+const MAX_CANDIDATES: usize = 60; // completion candidates shown below the prompt
+
+// a line starting with """ starts a multi-line question, ended by a line with """
+const MULTILINE_FENCE: &str = "\"\"\"";
+
+#[derive(PartialEq)]
+enum ViMode {
+    Insert,
+    Normal,
+}
+
+/**
+ * What a key press does to the line being edited
+ */
+enum Action {
+    Continue,
+    Submit,
+    Eof,
+}
+
+struct Search {
+    query: String,
+    match_index: Option<usize>, // the history entry shown, None if nothing matched yet
+    failed: bool,               // the query matches nothing older than the shown entry
+    saved: Vec<char>,           // the line before the search started, restored on cancel
+}
+
+pub struct LineEditor {
+    history: Vec<String>,
+    history_path: Option<PathBuf>,
+    vi: bool,
+    keybindings: Vec<(Key, String)>, // keys sending an input right away, from the config
+    stash: Option<String>, // the line being edited when a keybinding was pressed, restored after
+    // the line being edited
+    prompt: String,
+    buffer: Vec<char>,
+    cursor: usize,
+    rendered_cursor_row: usize, // rows between the prompt's first row and the cursor
+    history_index: usize,       // the history entry shown, history.len() for the new line
+    draft: Vec<char>,           // the new line, kept while browsing the history
+    kill_buffer: Vec<char>,     // text removed by ctrl-k/ctrl-u/ctrl-w and friends
+    vi_mode: ViMode,
+    vi_pending: Option<char>, // first key of a two key vi command(example: d of dd)
+    search: Option<Search>,
+    candidates: Vec<String>, // completion candidates shown below the line, until the next key
+}
+
+/**
+ * Where the terminal leaves the cursor after printing text from the start of a row, as (rows down,
+ * column). A wide character that doesn't fit at the end of a row goes to the next one. The column is
+ * the width when the text ends right at the end of a row, the terminal only wraps once more text
+ * comes.
+ */
+fn wrapped_position(text: &str, width: usize) -> (usize, usize) {
+    let (mut row, mut column) = (0, 0);
+    for grapheme in text.graphemes(true) {
+        let grapheme_width = width::grapheme_width(grapheme);
+        if column + grapheme_width > width {
+            row += 1;
+            column = 0;
+        }
+        column += grapheme_width;
+    }
+    return (row, column);
+}
+
+/**
+ * Parse a key name from the config(example: "ctrl-y", "alt-p", "f5")
+ */
+fn parse_key(name: &str) -> Option<Key> {
+    let name = name.to_lowercase();
+    let single_char = |text: &str| {
+        let mut chars = text.chars();
+        match (chars.next(), chars.next()) {
+            (Some(ch), None) => Some(ch),
+            _ => None,
+        }
+    };
+    if let Some(ch) = name.strip_prefix("ctrl-") {
+        return single_char(ch).map(Key::Ctrl);
+    }
+    if let Some(ch) = name.strip_prefix("alt-") {
+        return single_char(ch).map(Key::Alt);
+    }
+    if let Some(n) = name.strip_prefix('f') {
+        return n
+            .parse::<u8>()
+            .ok()
+            .filter(|n| *n >= 1 && *n <= 12)
+            .map(Key::F);
+    }
+    return None;
+}
+
+/**
+ * The history file, in the data directory
+ */
+fn history_file() -> Option<PathBuf> {
+    let mut path = config::data_dir()?;
+    path.push("history");
+    return Some(path);
+}
+
+fn is_word_char(ch: char) -> bool {
+    return ch.is_alphanumeric() || ch == '_';
+}
+
+/**
+ * If the text is a complete """ block, the question inside it. A block may fit on its opening
+ * line(example: """question""").
+ * returns None if the text isn't a """ block, Some(None) if the block isn't closed yet
+ */
+fn multiline_block(text: &str) -> Option<Option<String>> {
+    let first_line = text.lines().next().unwrap_or("");
+    let opening = first_line.trim_start().strip_prefix(MULTILINE_FENCE)?;
+    let lines: Vec<&str> = text.lines().collect();
+    if let Some(question) = opening.trim_end().strip_suffix(MULTILINE_FENCE) {
+        if lines.len() == 1 {
+            return Some(Some(question.trim().to_string()));
+        }
+    }
+    if lines.len() < 2 || lines[lines.len() - 1].trim() != MULTILINE_FENCE {
+        return Some(None);
+    }
+    let mut question = opening.trim().to_string();
+    for line in &lines[1..lines.len() - 1] {
+        if !question.is_empty() {
+            question.push('\n');
+        }
+        question.push_str(line);
+    }
+    return Some(Some(question));
+}
+
+impl LineEditor {
+    pub fn new() -> LineEditor {
+        let config = config::get();
+        let mut keybindings: Vec<(Key, String)> = Vec::new();
+        for (name, input) in &config.keybindings {
+            match parse_key(name) {
+                Some(key) => keybindings.push((key, input.clone())),
+                None => println!("Error: Unknown key {} in [keybindings]", name),
+            }
+        }
+        let mut editor = LineEditor {
+            history: Vec::new(),
+            history_path: history_file(),
+            vi: config.vi_mode,
+            keybindings,
+            stash: None,
+            prompt: String::new(),
+            buffer: Vec::new(),
+            cursor: 0,
+            rendered_cursor_row: 0,
+            history_index: 0,
+            draft: Vec::new(),
+            kill_buffer: Vec::new(),
+            vi_mode: ViMode::Insert,
+            vi_pending: None,
+            search: None,
+            candidates: Vec::new(),
+        };
+        editor.load_history();
+        return editor;
+    }
+
+    /**
+     * Read the history file, one json string per entry so that multi-line questions fit on a line.
+     * The file is rewritten when it grew past the limit.
+     */
+    fn load_history(&mut self) {
+        let path = match &self.history_path {
+            Some(path) => path,
+            None => return,
+        };
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(_) => return,
+        };
+        self.history = content
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_str::<String>(line).unwrap_or(line.to_string()))
+            .collect();
+        if self.history.len() > HISTORY_LIMIT {
+            self.history.drain(..self.history.len() - HISTORY_LIMIT);
+            let lines: Vec<String> = self
+                .history
+                .iter()
+                .map(|entry| serde_json::to_string(entry).unwrap())
+                .collect();
+            let _ = fs::write(path, lines.join("\n") + "\n");
+        }
+    }
+
+    /**
+     * Remember an input, in memory and in the history file. Repeating the previous input isn't
+     * remembered twice.
+     */
+    pub fn add_history(&mut self, entry: &str) {
+        if entry.trim().is_empty() || self.history.last().map(|last| last.as_str()) == Some(entry) {
+            return;
+        }
+        self.history.push(entry.to_string());
+        let path = match &self.history_path {
+            Some(path) => path,
+            None => return,
+        };
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+            let _ = writeln!(file, "{}", serde_json::to_string(entry).unwrap());
+        }
+    }
+
+    /**
+     * Read a line without a terminal, """ blocks still work
+     */
+    fn read_line_plain(&mut self, prompt: &str, initial: &str) -> Option<String> {
+        print!("{}{}", prompt, initial);
+        io::stdout().flush().unwrap();
+        let stdin = io::stdin();
+        let mut input = initial.to_string();
+        if BufRead::read_line(&mut stdin.lock(), &mut input).unwrap_or(0) == 0 {
+            println!();
+            return None;
+        }
+        while let Some(None) = multiline_block(input.trim_end()) {
+            print!("{}", CONTINUATION_PROMPT);
+            io::stdout().flush().unwrap();
+            if BufRead::read_line(&mut stdin.lock(), &mut input).unwrap_or(0) == 0 {
+                break;
+            }
+        }
+        return match multiline_block(input.trim_end()) {
+            Some(Some(question)) => Some(question),
+            _ => Some(input.trim_end_matches(['\n', '\r']).to_string()),
+        };
+    }
+
+    /**
+     * Read a line from the user, with editing, history and multi-line input
+     *
+     * @param prompt: printed before the line
+     * @param initial: text the line starts with, the user keeps typing after it
+     * @param complete: the tab completion of the line up to the cursor
+     * returns the line, None once the input ends(ctrl-d on an empty line)
+     */
+    pub fn read_line(
+        &mut self,
+        prompt: &str,
+        initial: &str,
+        complete: &dyn Fn(&str) -> Completion,
+    ) -> Option<String> {
+        if !termion::is_tty(&io::stdin()) || !termion::is_tty(&io::stdout()) {
+            return self.read_line_plain(prompt, initial);
+        }
+        let initial = match self.stash.take() {
+            Some(stash) if initial.is_empty() => stash,
+            _ => initial.to_string(),
+        };
+
+        self.prompt = prompt.to_string();
+        self.buffer = initial.chars().collect();
+        self.cursor = self.buffer.len();
+        self.rendered_cursor_row = 0;
+        self.history_index = self.history.len();
+        self.draft.clear();
+        self.vi_mode = ViMode::Insert;
+        self.vi_pending = None;
+        self.search = None;
+        self.candidates.clear();
+
+        let mut stdout = match io::stdout().into_raw_mode() {
+            Ok(stdout) => stdout,
+            Err(_) => return self.read_line_plain(prompt, &initial),
+        };
+        self.render(&mut stdout);
+
+        let stdin = io::stdin();
+        let mut keys = stdin.lock().keys();
+        let result = loop {
+            let key = match keys.next() {
+                Some(Ok(key)) => key,
+                _ => break None,
+            };
+            match self.handle_key(key, &mut stdout, complete) {
+                Action::Continue => self.render(&mut stdout),
+                Action::Submit => {
+                    let text: String = self.buffer.iter().collect();
+                    match multiline_block(&text) {
+                        Some(None) => {
+                            self.insert('\n');
+                            self.render(&mut stdout);
+                            continue;
+                        }
+                        Some(Some(question)) => break Some(question),
+                        None => break Some(text),
+                    }
+                }
+                Action::Eof => break None,
+            }
+        };
+
+        // leave the cursor below the input, the answer starts there
+        self.search = None;
+        self.candidates.clear();
+        self.cursor = self.buffer.len();
+        self.render(&mut stdout);
+        let _ = write!(stdout, "\r\n");
+        let _ = stdout.flush();
+        return result;
+    }
+
+    /**
+     * Draw the prompt and the line, replacing what was drawn before, and place the cursor
+     */
+    fn render<W: Write>(&mut self, out: &mut W) {
+        let width = terminal::width();
+        let prompt = match &self.search {
+            Some(search) if search.failed => {
+                format!("(failed reverse-i-search)`{}': ", search.query)
+            }
+            Some(search) => format!("(reverse-i-search)`{}': ", search.query),
+            None => self.prompt.clone(),
+        };
+        let text: String = self.buffer.iter().collect();
+
+        // back to the first row of the prompt
+        if self.rendered_cursor_row > 0 {
+            let _ = write!(out, "{}", cursor::Up(self.rendered_cursor_row as u16));
+        }
+        let _ = write!(out, "\r{}", clear::AfterCursor);
+
+        let lines: Vec<&str> = text.split('\n').collect();
+        let mut row = 0; // first row of the current line
+        let mut offset = 0; // chars of the buffer before the current line
+        let mut cursor_position: Option<(usize, usize)> = None;
+        for (i, line) in lines.iter().enumerate() {
+            let prefix = if i == 0 {
+                prompt.as_str()
+            } else {
+                CONTINUATION_PROMPT
+            };
+            let _ = write!(out, "{}{}", prefix, line);
+            let line_len = line.chars().count();
+            if cursor_position.is_none() && self.cursor <= offset + line_len {
+                let before: String = line.chars().take(self.cursor - offset).collect();
+                let (cursor_row, column) = wrapped_position(&(prefix.to_string() + &before), width);
+                cursor_position = match column == width {
+                    true => Some((row + cursor_row + 1, 0)),
+                    false => Some((row + cursor_row, column)),
+                };
+            }
+            let (end_row, end_column) = wrapped_position(&(prefix.to_string() + line), width);
+            if i + 1 < lines.len() {
+                let _ = write!(out, "\r\n");
+                row += end_row + 1;
+            } else {
+                // at the very end of a row the terminal waits before wrapping, wrap right away
+                if end_column == width {
+                    let _ = write!(out, "\r\n");
+                    row += 1;
+                }
+                row += end_row;
+            }
+            offset += line_len + 1;
+        }
+
+        if !self.candidates.is_empty() {
+            row += self.render_candidates(out, width);
+        }
+
+        let (cursor_row, cursor_column) = cursor_position.unwrap_or((row, 0));
+        if row > cursor_row {
+            let _ = write!(out, "{}", cursor::Up((row - cursor_row) as u16));
+        }
+        let _ = write!(out, "\r");
+        if cursor_column > 0 {
+            let _ = write!(out, "{}", cursor::Right(cursor_column as u16));
+        }
+        self.rendered_cursor_row = cursor_row;
+        let _ = out.flush();
+    }
+
+    /**
+     * Draw the completion candidates in columns below the line
+     * returns the rows drawn
+     */
+    fn render_candidates<W: Write>(&self, out: &mut W, width: usize) -> usize {
+        let shown = &self.candidates[..std::cmp::min(self.candidates.len(), MAX_CANDIDATES)];
+        let column_width = shown
+            .iter()
+            .map(|candidate| width::display_width(candidate))
+            .max()
+            .unwrap_or(0)
+            + 2;
+        let columns = std::cmp::max(1, width / column_width);
+        let mut rows = 0;
+        for chunk in shown.chunks(columns) {
+            let _ = write!(out, "\r\n");
+            for candidate in chunk {
+                let padding = column_width - width::display_width(candidate);
+                let _ = write!(out, "{}{}", candidate, " ".repeat(padding));
+            }
+            rows += 1;
+        }
+        if self.candidates.len() > shown.len() {
+            let more = self.candidates.len() - shown.len();
+            let _ = write!(out, "\r\n... and {} more", more);
+            rows += 1;
+        }
+        return rows;
+    }
+
+    /**
+     * Complete the word before the cursor. A single candidate replaces the word, several are
+     * completed up to their common prefix and shown below the line.
+     */
+    fn complete(&mut self, complete: &dyn Fn(&str) -> Completion) {
+        let line_start = self.line_start();
+        let line: String = self.buffer[line_start..self.cursor].iter().collect();
+        let completion = complete(&line);
+        let word_len = line.chars().count() - completion.start;
+        let replacement: Vec<char> = match completion.candidates.as_slice() {
+            [] => return,
+            [candidate] => {
+                let mut replacement: Vec<char> = candidate.chars().collect();
+                if !candidate.ends_with('/') {
+                    replacement.push(' ');
+                }
+                replacement
+            }
+            [first, rest @ ..] => {
+                let mut common: Vec<char> = first.chars().collect();
+                for candidate in rest {
+                    let same = common
+                        .iter()
+                        .zip(candidate.chars())
+                        .take_while(|(a, b)| **a == *b)
+                        .count();
+                    common.truncate(same);
+                }
+                if common.len() <= word_len {
+                    self.candidates = completion.candidates;
+                }
+                common
+            }
+        };
+        let start = line_start + completion.start;
+        let after = self.buffer.split_off(self.cursor);
+        self.buffer.truncate(start);
+        self.buffer.extend(replacement.iter());
+        self.cursor = self.buffer.len();
+        self.buffer.extend(after);
+    }
+
+    fn insert(&mut self, ch: char) {
+        self.buffer.insert(self.cursor, ch);
+        self.cursor += 1;
+    }
+
+    /**
+     * Where the grapheme before the cursor starts, so that the cursor never lands inside a
+     * character made of several chars(example: an e and its combining accent)
+     */
+    fn grapheme_left(&self) -> usize {
+        let text: String = self.buffer[..self.cursor].iter().collect();
+        return match text.graphemes(true).next_back() {
+            Some(grapheme) => self.cursor - grapheme.chars().count(),
+            None => 0,
+        };
+    }
+
+    /**
+     * Where the grapheme after the cursor ends
+     */
+    fn grapheme_right(&self) -> usize {
+        let text: String = self.buffer[self.cursor..].iter().collect();
+        return match text.graphemes(true).next() {
+            Some(grapheme) => self.cursor + grapheme.chars().count(),
+            None => self.buffer.len(),
+        };
+    }
+
+    fn line_start(&self) -> usize {
+        return self.buffer[..self.cursor]
+            .iter()
+            .rposition(|ch| *ch == '\n')
+            .map(|i| i + 1)
+            .unwrap_or(0);
+    }
+
+    fn line_end(&self) -> usize {
+        return self.buffer[self.cursor..]
+            .iter()
+            .position(|ch| *ch == '\n')
+            .map(|i| self.cursor + i)
+            .unwrap_or(self.buffer.len());
+    }
+
+    fn word_left(&self) -> usize {
+        let mut i = self.cursor;
+        while i > 0 && !is_word_char(self.buffer[i - 1]) {
+            i -= 1;
+        }
+        while i > 0 && is_word_char(self.buffer[i - 1]) {
+            i -= 1;
+        }
+        return i;
+    }
+
+    // the end of the current or next word, like emacs' alt-f
+    fn word_right(&self) -> usize {
+        let mut i = self.cursor;
+        while i < self.buffer.len() && !is_word_char(self.buffer[i]) {
+            i += 1;
+        }
+        while i < self.buffer.len() && is_word_char(self.buffer[i]) {
+            i += 1;
+        }
+        return i;
+    }
+
+    // the start of the next word, like vi's w
+    fn next_word_start(&self) -> usize {
+        let mut i = self.cursor;
+        while i < self.buffer.len() && is_word_char(self.buffer[i]) {
+            i += 1;
+        }
+        while i < self.buffer.len() && !is_word_char(self.buffer[i]) {
+            i += 1;
+        }
+        return i;
+    }
+
+    /**
+     * Remove the text between two positions, keeping it for yanking
+     */
+    fn kill(&mut self, from: usize, to: usize) {
+        if from >= to {
+            return;
+        }
+        self.kill_buffer = self.buffer.drain(from..to).collect();
+        self.cursor = from;
+    }
+
+    fn yank(&mut self) {
+        let killed = self.kill_buffer.clone();
+        for ch in killed {
+            self.insert(ch);
+        }
+    }
+
+    fn set_buffer(&mut self, text: &[char]) {
+        self.buffer = text.to_vec();
+        self.cursor = self.buffer.len();
+    }
+
+    /**
+     * Move the cursor to the previous line of a multi-line input, or show the previous history
+     * entry when on the first line
+     */
+    fn up(&mut self) {
+        let start = self.line_start();
+        if start > 0 {
+            let column = self.cursor - start;
+            self.cursor = start - 1;
+            let previous_start = self.line_start();
+            self.cursor = std::cmp::min(previous_start + column, start - 1);
+            return;
+        }
+        if self.history_index == 0 {
+            return;
+        }
+        if self.history_index == self.history.len() {
+            self.draft = self.buffer.clone();
+        }
+        self.history_index -= 1;
+        let entry: Vec<char> = self.history[self.history_index].chars().collect();
+        self.set_buffer(&entry);
+    }
+
+    fn down(&mut self) {
+        let end = self.line_end();
+        if end < self.buffer.len() {
+            let column = self.cursor - self.line_start();
+            self.cursor = end + 1;
+            self.cursor = std::cmp::min(self.cursor + column, self.line_end());
+            return;
+        }
+        if self.history_index >= self.history.len() {
+            return;
+        }
+        self.history_index += 1;
+        let entry: Vec<char> = if self.history_index == self.history.len() {
+            self.draft.clone()
+        } else {
+            self.history[self.history_index].chars().collect()
+        };
+        self.set_buffer(&entry);
+    }
+
+    /**
+     * Show the newest history entry older than `before` that contains the search query
+     */
+    fn search_history(&mut self, before: usize) {
+        let search = match &mut self.search {
+            Some(search) => search,
+            None => return,
+        };
+        let found = (0..before)
+            .rev()
+            .find(|i| self.history[*i].contains(&search.query));
+        match found {
+            Some(i) => {
+                search.match_index = Some(i);
+                search.failed = false;
+                let entry = &self.history[i];
+                let byte_index = entry.find(&search.query).unwrap_or(0);
+                self.buffer = entry.chars().collect();
+                self.cursor = entry[..byte_index].chars().count();
+                self.history_index = i;
+            }
+            None => search.failed = !search.query.is_empty(),
+        }
+    }
+
+    /**
+     * Keys while searching the history with ctrl-r
+     * returns the action, or None to leave the search and handle the key as usual
+     */
+    fn handle_search_key(&mut self, key: Key) -> Option<Action> {
+        let search = self.search.as_mut().unwrap();
+        match key {
+            Key::Char('\n') => {
+                self.search = None;
+                return Some(Action::Submit);
+            }
+            Key::Char(ch) if !ch.is_control() => {
+                search.query.push(ch);
+                self.search_history(self.history.len());
+            }
+            Key::Backspace | Key::Ctrl('h') => {
+                search.query.pop();
+                self.search_history(self.history.len());
+            }
+            Key::Ctrl('r') => {
+                let before = search.match_index.unwrap_or(self.history.len());
+                self.search_history(before);
+            }
+            Key::Ctrl('g') | Key::Ctrl('c') | Key::Esc => {
+                let saved = search.saved.clone();
+                self.search = None;
+                self.set_buffer(&saved);
+                self.history_index = self.history.len();
+            }
+            _ => {
+                self.search = None;
+                return None;
+            }
+        }
+        return Some(Action::Continue);
+    }
+
+    /**
+     * Keys of vi's normal mode
+     * returns the action, or None if the key isn't a normal mode key
+     */
+    fn handle_vi_normal_key(&mut self, key: Key) -> Option<Action> {
+        let pending = self.vi_pending.take();
+        match (pending, key) {
+            (Some('d'), Key::Char('d')) => {
+                let all = self.buffer.len();
+                self.kill(0, all);
+            }
+            (Some('d'), Key::Char('w')) => self.kill(self.cursor, self.next_word_start()),
+            (Some('d'), Key::Char('b')) => self.kill(self.word_left(), self.cursor),
+            (Some('c'), Key::Char('w')) => {
+                self.kill(self.cursor, self.word_right());
+                self.vi_mode = ViMode::Insert;
+            }
+            (Some(_), _) => {}
+            (None, Key::Char('d')) | (None, Key::Char('c')) => {
+                if let Key::Char(ch) = key {
+                    self.vi_pending = Some(ch);
+                }
+            }
+            (None, Key::Char('h')) => self.cursor = self.grapheme_left(),
+            (None, Key::Char('l')) => {
+                self.cursor = std::cmp::min(self.grapheme_right(), self.line_end());
+            }
+            (None, Key::Char('0')) => self.cursor = self.line_start(),
+            (None, Key::Char('$')) => self.cursor = self.line_end(),
+            (None, Key::Char('^')) => {
+                self.cursor = self.line_start();
+                while self.cursor < self.line_end() && self.buffer[self.cursor].is_whitespace() {
+                    self.cursor += 1;
+                }
+            }
+            (None, Key::Char('w')) => self.cursor = self.next_word_start(),
+            (None, Key::Char('b')) => self.cursor = self.word_left(),
+            (None, Key::Char('e')) => {
+                self.cursor = std::cmp::min(self.cursor + 1, self.buffer.len());
+                self.cursor = std::cmp::max(self.word_right(), 1) - 1;
+            }
+            (None, Key::Char('x')) => {
+                if self.cursor < self.line_end() {
+                    self.kill(self.cursor, self.grapheme_right());
+                }
+            }
+            (None, Key::Char('X')) => {
+                if self.cursor > self.line_start() {
+                    self.kill(self.grapheme_left(), self.cursor);
+                }
+            }
+            (None, Key::Char('D')) => self.kill(self.cursor, self.line_end()),
+            (None, Key::Char('C')) => {
+                self.kill(self.cursor, self.line_end());
+                self.vi_mode = ViMode::Insert;
+            }
+            (None, Key::Char('S')) => {
+                let all = self.buffer.len();
+                self.kill(0, all);
+                self.vi_mode = ViMode::Insert;
+            }
+            (None, Key::Char('i')) => self.vi_mode = ViMode::Insert,
+            (None, Key::Char('a')) => {
+                self.cursor = std::cmp::min(self.grapheme_right(), self.line_end());
+                self.vi_mode = ViMode::Insert;
+            }
+            (None, Key::Char('I')) => {
+                self.cursor = self.line_start();
+                self.vi_mode = ViMode::Insert;
+            }
+            (None, Key::Char('A')) => {
+                self.cursor = self.line_end();
+                self.vi_mode = ViMode::Insert;
+            }
+            (None, Key::Char('p')) => {
+                self.cursor = self.grapheme_right();
+                self.yank();
+            }
+            (None, Key::Char('P')) => self.yank(),
+            (None, Key::Char('k')) => self.up(),
+            (None, Key::Char('j')) => self.down(),
+            _ => return None,
+        }
+        return Some(Action::Continue);
+    }
+
+    fn handle_key<W: Write>(
+        &mut self,
+        key: Key,
+        out: &mut W,
+        complete: &dyn Fn(&str) -> Completion,
+    ) -> Action {
+        self.candidates.clear();
+        if self.search.is_some() {
+            if let Some(action) = self.handle_search_key(key) {
+                return action;
+            }
+        }
+        if let Some((_, input)) = self.keybindings.iter().find(|(bound, _)| *bound == key) {
+            let input = input.clone();
+            if !self.buffer.is_empty() {
+                self.stash = Some(self.buffer.iter().collect());
+            }
+            self.set_buffer(&input.chars().collect::<Vec<char>>());
+            return Action::Submit;
+        }
+        if self.vi && self.vi_mode == ViMode::Normal {
+            if let Some(action) = self.handle_vi_normal_key(key) {
+                return action;
+            }
+        }
+
+        match key {
+            Key::Char('\n') => return Action::Submit,
+            Key::Alt('\r') | Key::Alt('\n') => self.insert('\n'),
+            Key::Char('\t') if self.vi_mode == ViMode::Insert => self.complete(complete),
+            Key::Char(ch) if !ch.is_control() && self.vi_mode == ViMode::Insert => {
+                self.insert(ch);
+            }
+            Key::Esc if self.vi && self.vi_mode == ViMode::Insert => {
+                self.vi_mode = ViMode::Normal;
+                if self.cursor > self.line_start() {
+                    self.cursor = self.grapheme_left();
+                }
+            }
+            Key::Ctrl('d') => {
+                if self.buffer.is_empty() {
+                    return Action::Eof;
+                }
+                let end = self.grapheme_right();
+                self.buffer.drain(self.cursor..end);
+            }
+            Key::Ctrl('c') => {
+                // drop the line and start over on a fresh prompt
+                self.cursor = self.buffer.len();
+                self.render(out);
+                let _ = write!(out, "^C\r\n");
+                self.buffer.clear();
+                self.cursor = 0;
+                self.rendered_cursor_row = 0;
+                self.history_index = self.history.len();
+                self.vi_mode = ViMode::Insert;
+            }
+            Key::Ctrl('l') => {
+                let _ = write!(out, "{}{}", clear::All, cursor::Goto(1, 1));
+                self.rendered_cursor_row = 0;
+            }
+            Key::Left | Key::Ctrl('b') => self.cursor = self.grapheme_left(),
+            Key::Right | Key::Ctrl('f') => self.cursor = self.grapheme_right(),
+            Key::Home | Key::Ctrl('a') => self.cursor = self.line_start(),
+            Key::End | Key::Ctrl('e') => self.cursor = self.line_end(),
+            Key::Alt('b') => self.cursor = self.word_left(),
+            Key::Alt('f') => self.cursor = self.word_right(),
+            Key::Up | Key::Ctrl('p') => self.up(),
+            Key::Down | Key::Ctrl('n') => self.down(),
+            Key::Backspace | Key::Ctrl('h') => {
+                let start = self.grapheme_left();
+                self.buffer.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            Key::Delete => {
+                let end = self.grapheme_right();
+                self.buffer.drain(self.cursor..end);
+            }
+            Key::Ctrl('k') => {
+                // at the end of a line, join it with the next one
+                let end = std::cmp::max(self.line_end(), self.cursor + 1);
+                self.kill(self.cursor, std::cmp::min(end, self.buffer.len()));
+            }
+            Key::Ctrl('u') => self.kill(self.line_start(), self.cursor),
+            Key::Ctrl('w') | Key::Alt('\x7f') => self.kill(self.word_left(), self.cursor),
+            Key::Alt('d') => self.kill(self.cursor, self.word_right()),
+            Key::Ctrl('y') => self.yank(),
+            Key::Ctrl('r') => {
+                self.search = Some(Search {
+                    query: String::new(),
+                    match_index: None,
+                    failed: false,
+                    saved: self.buffer.clone(),
+                });
+            }
+            _ => {}
+        }
+        return Action::Continue;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor(text: &str, cursor: usize) -> LineEditor {
+        let mut editor = LineEditor::new();
+        editor.buffer = text.chars().collect();
+        editor.cursor = cursor;
+        return editor;
+    }
+
+    #[test]
+    fn multiline_block_needs_its_closing_fence() {
+        assert_eq!(multiline_block("a question"), None);
+        assert_eq!(multiline_block("\"\"\""), Some(None));
+        assert_eq!(multiline_block("\"\"\"first\nsecond"), Some(None));
+        assert_eq!(
+            multiline_block("\"\"\"first\nsecond\n\"\"\""),
+            Some(Some("first\nsecond".to_string()))
+        );
+        assert_eq!(
+            multiline_block("\"\"\"\n  indented\n\n\"\"\""),
+            Some(Some("  indented\n".to_string()))
+        );
+    }
+
+    #[test]
+    fn multiline_block_on_one_line() {
+        assert_eq!(
+            multiline_block("\"\"\"a question\"\"\""),
+            Some(Some("a question".to_string()))
+        );
+        assert_eq!(
+            multiline_block("  \"\"\" spaced \"\"\"  "),
+            Some(Some("spaced".to_string()))
+        );
+        assert_eq!(multiline_block("\"\"\"\"\"\""), Some(Some(String::new())));
+    }
+
+    #[test]
+    fn wrapped_position_counts_columns() {
+        assert_eq!(wrapped_position("", 10), (0, 0));
+        assert_eq!(wrapped_position("abc", 10), (0, 3));
+        // the terminal only wraps once more text comes
+        assert_eq!(wrapped_position("abcde", 5), (0, 5));
+        assert_eq!(wrapped_position("abcdef", 5), (1, 1));
+        assert_eq!(wrapped_position("中文字", 10), (0, 6));
+        assert_eq!(
+            wrapped_position("e\u{301}👨\u{200d}👩\u{200d}👧", 10),
+            (0, 3)
+        );
+    }
+
+    #[test]
+    fn wrapped_position_moves_wide_characters_that_dont_fit() {
+        // 中 would take the 5th and 6th columns of a 5 column row
+        assert_eq!(wrapped_position("abcd中", 5), (1, 2));
+        assert_eq!(wrapped_position("中文中", 5), (1, 2));
+        assert_eq!(wrapped_position("中文中文中", 4), (2, 2));
+    }
+
+    #[test]
+    fn parse_key_names() {
+        assert_eq!(parse_key("ctrl-y"), Some(Key::Ctrl('y')));
+        assert_eq!(parse_key("Alt-P"), Some(Key::Alt('p')));
+        assert_eq!(parse_key("f5"), Some(Key::F(5)));
+        assert_eq!(parse_key("f13"), None);
+        assert_eq!(parse_key("ctrl-yy"), None);
+        assert_eq!(parse_key("shift-a"), None);
+    }
+
+    #[test]
+    fn grapheme_motions_skip_whole_characters() {
+        // e, combining accent, the family emoji(5 chars joined by zero width joiners), x
+        let text = "e\u{301}👨\u{200d}👩\u{200d}👧x";
+        assert_eq!(editor(text, 0).grapheme_right(), 2);
+        assert_eq!(editor(text, 2).grapheme_right(), 7);
+        assert_eq!(editor(text, 7).grapheme_left(), 2);
+        assert_eq!(editor(text, 2).grapheme_left(), 0);
+        assert_eq!(editor(text, 8).grapheme_right(), 8);
+    }
+
+    #[test]
+    fn word_motions() {
+        let text = "let x_1 = foo(bar);";
+        assert_eq!(editor(text, 0).word_right(), 3);
+        assert_eq!(editor(text, 3).word_right(), 7);
+        assert_eq!(editor(text, 19).word_left(), 14);
+        assert_eq!(editor(text, 14).word_left(), 10);
+        assert_eq!(editor(text, 0).next_word_start(), 4);
+        assert_eq!(editor(text, 10).next_word_start(), 14);
+    }
+}
@@ -7,9 +7,16 @@ use reqwest::header::{HeaderMap, HeaderValue};
 use reqwest::{Client, Error};
 use std::io::{self, Write};
 
+use crate::config;
+
 use serde_json::{from_slice, Value};
-use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
 use tokio::runtime::Runtime;
+
+// models offered by \model's completion, any other name the API takes works too
+pub const KNOWN_MODELS: [&str; 5] = ["gpt-3.5-turbo", "gpt-4", "gpt-4o", "gpt-4o-mini", "o1-mini"];
+
 pub enum LLMRole {
     User,
     Assistant,
@@ -26,6 +33,28 @@ impl Clone for LLMRole {
     }
 }
 
+impl LLMRole {
+    /**
+     * The role's name as used by the chat completions API
+     */
+    pub fn as_str(&self) -> &'static str {
+        return match self {
+            LLMRole::User => "user",
+            LLMRole::Assistant => "assistant",
+            LLMRole::System => "system",
+        };
+    }
+
+    pub fn from_name(name: &str) -> Option<LLMRole> {
+        return match name {
+            "user" => Some(LLMRole::User),
+            "assistant" => Some(LLMRole::Assistant),
+            "system" => Some(LLMRole::System),
+            _ => None,
+        };
+    }
+}
+
 pub struct LLMMessage {
     pub owner: LLMRole,
     pub content: String,
@@ -50,52 +79,16 @@ pub trait LLM {
     //TODO: implement these
     // fn to_json(&self, json_path: &str);
     // fn from_json(&mut self, json_path: &str);
-    fn get_code_blocks(&self) -> HashMap<String, String>; //TODO: this shouldn't be a part of LLM
 }
 
 pub struct CopilotChat {
     api_request_header: HeaderMap,
-    query_json: Value, // a json value, conains all past conversation
+    query_json: Value,          // a json value, conains all past conversation
+    last_usage: Option<Value>,  // token usage of the last query, if the API reported it
+    last_model: Option<String>, // model that answered the last query, if the API reported it
 }
 
 impl LLM for CopilotChat {
-    fn get_code_blocks(&self) -> HashMap<String, String> {
-        let mut code_blocks: HashMap<String, String> = HashMap::new();
-        let messages = self.query_json["messages"].as_array().unwrap();
-        for message in messages {
-            let role = message["role"].as_str().unwrap();
-            if role != "assistant" {
-                continue;
-            }
-            let content = message["content"].as_str().unwrap();
-            if content.contains("```") {
-                let lines = content.split("\n").collect::<Vec<&str>>();
-                let mut code_block: String = String::new();
-                let mut code_block_name: String = String::new();
-                let mut in_code_block = false;
-                for line in lines {
-                    if line.contains("```") {
-                        if in_code_block {
-                            code_blocks.insert(code_block_name.clone(), code_block.clone());
-                            code_block.clear();
-                            code_block_name.clear();
-                            in_code_block = false;
-                        } else {
-                            code_block_name = line.replace("```", "").trim().to_string();
-                            in_code_block = true;
-                        }
-                    } else {
-                        if in_code_block {
-                            code_block.push_str(line);
-                            code_block.push_str("\n");
-                        }
-                    }
-                }
-            }
-        }
-        return code_blocks;
-    }
-
     // TODO: re-implement jail breaking
     fn query<F: Fn(&str)>(
         &mut self,
@@ -104,11 +97,7 @@ impl LLM for CopilotChat {
     ) -> Result<String, Error> {
         self.query_json["messages"] = serde_json::json!([]); // clear the chat history
         for message in chat_history {
-            let role_str = match message.owner {
-                LLMRole::User => "user",
-                LLMRole::Assistant => "assistant",
-                LLMRole::System => "system",
-            };
+            let role_str = message.owner.as_str();
             match self.query_json["messages"].as_array_mut() {
                 None => {
                     self.query_json["messages"] = serde_json::json!([{
@@ -125,24 +114,31 @@ impl LLM for CopilotChat {
             }
         }
 
+        // 400s are copilot rejecting the question, which it often accepts when asked again
+        let retry = config::get();
         let rt = Runtime::new().unwrap();
-        let res = rt.block_on(self.stream_copilot_request(message_sink));
-
-        match res {
-            Ok(ai_output) => {
-                return Result::Ok(ai_output.unwrap_or("".to_string()));
-            }
-            Err(e) => {
-                match e.status() {
-                    Some(status) => match status.as_u16() {
-                        401 => {
-                            self.update_jwt_token();
-                        }
-                        _ => {}
-                    },
-                    None => {}
+        let mut attempt: u32 = 0;
+        loop {
+            self.last_usage = None;
+            self.last_model = None;
+            match rt.block_on(self.stream_copilot_request(&message_sink)) {
+                Ok(ai_output) => {
+                    return Result::Ok(ai_output.unwrap_or("".to_string()));
+                }
+                Err(e) => {
+                    let status = e.status().map(|status| status.as_u16());
+                    if status == Some(401) {
+                        self.update_jwt_token();
+                    }
+                    // errors in the middle of a response aren't retried, part of it was printed
+                    let retryable = matches!(status, Some(400 | 401 | 429 | 500..=599));
+                    if !retryable || attempt >= retry.retry_attempts {
+                        return Result::Err(e);
+                    }
+                    let backoff = retry.retry_backoff_ms << std::cmp::min(attempt, 10);
+                    thread::sleep(Duration::from_millis(backoff));
+                    attempt += 1;
                 }
-                return Result::Err(e);
             }
         }
     }
@@ -228,7 +224,12 @@ impl CopilotChat {
         // read a line that was sent back. copilot responses are sent in lines json-like bytes
         // each line returned by a api is either empty or a json-object. The line is also prefixed
         // with data: so we need to remove that prefix
-        fn read_line(line: &str) -> Option<String> {
+        // token usage and the model that answered are picked up from whichever line carries them
+        fn read_line(
+            line: &str,
+            usage: &mut Option<Value>,
+            model: &mut Option<String>,
+        ) -> Option<String> {
             // println!("line: {:?}", line);
             if !line.contains("data") {
                 return None;
@@ -238,6 +239,14 @@ impl CopilotChat {
             }
             let to_parse = &line[6..];
             let json_res: Value = serde_json::from_str(to_parse).unwrap();
+            if let Some(line_usage) = json_res.get("usage").filter(|u| !u.is_null()) {
+                *usage = Some(line_usage.clone());
+            }
+            if let Some(line_model) = json_res.get("model").and_then(|m| m.as_str()) {
+                if !line_model.is_empty() {
+                    *model = Some(line_model.to_string());
+                }
+            }
             if json_res.get("choices").is_none() {
                 return None;
             }
@@ -287,7 +296,8 @@ impl CopilotChat {
                     }
                 }
                 for i in 0..iter_range {
-                    let partial_ai_response = read_line(lines[i]);
+                    let partial_ai_response =
+                        read_line(lines[i], &mut self.last_usage, &mut self.last_model);
                     if partial_ai_response.is_some() {
                         callback(&partial_ai_response.as_ref().unwrap());
                         ai_response.push_str(&partial_ai_response.unwrap());
@@ -321,6 +331,44 @@ impl CopilotChat {
         return Ok(Some(ai_response));
     }
 
+    /**
+     * The model that answered the last query, or the requested model if the API didn't say
+     */
+    pub fn model(&self) -> String {
+        return match &self.last_model {
+            Some(model) => model.clone(),
+            None => self.query_json["model"].as_str().unwrap_or("").to_string(),
+        };
+    }
+
+    /**
+     * Set a request parameter(example: "temperature") sent along with every following query
+     *
+     * @param name: the parameter's name in the chat completions request
+     * @param value: its value, overriding the default. null removes the parameter
+     */
+    pub fn set_param(&mut self, name: &str, value: Value) {
+        if value.is_null() {
+            self.query_json.as_object_mut().unwrap().remove(name);
+            return;
+        }
+        self.query_json[name] = value;
+    }
+
+    /**
+     * The current value of a request parameter, null if it isn't set
+     */
+    pub fn param(&self, name: &str) -> Value {
+        return self.query_json[name].clone();
+    }
+
+    /**
+     * Token usage of the last query, as reported by the API
+     */
+    pub fn last_usage(&self) -> Option<Value> {
+        return self.last_usage.clone();
+    }
+
     async fn get_jwt_token() -> Option<String> {
         let client = reqwest::Client::new();
 
@@ -368,6 +416,7 @@ impl CopilotChat {
         return Some(token);
     }
     pub fn new() -> CopilotChat {
+        let config = config::get();
         let map: HeaderMap = [
             ("x-request-id", "9d4f79c9-7104-4e24-a3ac-73349f95af63"),
             ("openai-organization", "github-copilot"),
@@ -395,15 +444,22 @@ impl CopilotChat {
             query_json: serde_json::json!({
                 "intent": true,
                 "messages": [],
-                "model": "gpt-4",
+                "model": config.model,
                 // # "model": "copilot-chat",
                 "n": 1,
                 "stream": true,
-                "temperature": 0.1,
-                "top_p": 1,
+                // the last chunk of the stream carries the token usage
+                "stream_options": { "include_usage": true },
+                "temperature": config.temperature,
+                "top_p": config.top_p,
             }),
+            last_usage: None,
+            last_model: None,
         };
 
+        if let Some(max_tokens) = config.max_tokens {
+            ret.set_param("max_tokens", Value::from(max_tokens));
+        }
         ret.update_jwt_token();
 
         return ret;
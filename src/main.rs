@@ -1,33 +1,493 @@
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
+mod attachment;
+mod batch;
 mod chat;
 mod codeblock_builder;
+mod command_registry;
+mod completion;
+mod config;
+mod conversation;
+mod diff;
+mod edit_block;
+mod export;
+mod file_ops;
+mod git;
+mod line_editor;
 mod llm;
+mod markdown;
+mod patch;
+mod plugin;
+mod prompt;
+mod runner;
 mod syntax;
+mod terminal;
+mod width;
 use clipboard::ClipboardContext;
 use clipboard::ClipboardProvider;
-use codeblock_builder::{CodeBlockBuilder, CodeBlockBuilderState};
+use codeblock_builder::{CodeBlock, CodeBlockBuilder, CodeBlockBuilderState};
+use command_registry::{ArgSpec, Command, CommandRegistry, CommandResult};
 
-use chat::Chat;
-use std::io::{self, Write};
-use termion::{clear, cursor, terminal_size};
+use chat::{Chat, OutputMode};
+use export::ExportFormat;
+use line_editor::LineEditor;
+use llm::{CopilotChat, LLMMessage, LLMRole, LLM};
+use std::fs;
+use std::io::{self, Read, Write};
+use termion::{clear, cursor};
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
+// set when the output is piped or not meant for humans, only the model's output is printed then
+static RAW_OUTPUT: AtomicBool = AtomicBool::new(false);
+
 fn print_separator() {
-    let line_width = terminal_size().unwrap().0 as usize;
+    if RAW_OUTPUT.load(Ordering::Relaxed) {
+        return;
+    }
+    let line_width = terminal::width();
     println!("{}", "-".repeat(line_width));
     io::stdout().flush().unwrap();
 }
 
-fn print_prompt() {
-    print!(">> ");
-    io::stdout().flush().unwrap();
+/**
+ * Copy code blocks to the clipboard
+ *
+ * @param args: "" for the last code block, "N" for code block N, "all" for every code block
+ */
+fn yank_code_blocks(chat: &Chat, args: &str) {
+    let code_blocks = chat.get_code_blocks();
+    let to_yank = match args {
+        "" => code_blocks.last().map(|code_block| code_block.code.clone()),
+        "all" if !code_blocks.is_empty() => Some(
+            code_blocks
+                .iter()
+                .map(|code_block| code_block.code.as_str())
+                .collect::<Vec<&str>>()
+                .join("\n"),
+        ),
+        label => match label.parse::<usize>() {
+            Ok(label) => chat.get_code_block(label).map(|code_block| code_block.code),
+            Err(_) => {
+                println!("Usage: \\y [N|all]");
+                return;
+            }
+        },
+    };
+
+    match to_yank {
+        Some(code) => {
+            let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
+            match ctx.set_contents(code) {
+                Ok(_) => println!("Yanked to clipboard"),
+                Err(_) => println!("Error: Could not set clipboard contents"),
+            }
+        }
+        None => println!("No such code block. Type \\blocks to list them"),
+    }
+}
+
+/**
+ * Attach the files matching a path or glob to the next question
+ */
+fn attach_files(chat: &mut Chat, pattern: &str) {
+    for attachment in attachment::collect_attachments(pattern, chat.attachment_budget_left()) {
+        chat.attach(attachment);
+    }
+}
+
+fn get_clipboard_contents() -> Option<String> {
+    let mut ctx: ClipboardContext = ClipboardProvider::new().ok()?;
+    return ctx.get_contents().ok();
+}
+
+/**
+ * Look up a code block by a number argument, telling the user how to list them if it doesn't exist
+ */
+fn code_block_arg(chat: &Chat, label: &str) -> Option<(usize, CodeBlock)> {
+    let label = label.parse::<usize>().unwrap_or(0);
+    match chat.get_code_block(label) {
+        Some(code_block) => return Some((label, code_block)),
+        None => {
+            println!("No code block {}. Type \\blocks to list them", label);
+            return None;
+        }
+    }
+}
+
+/**
+ * The commands available in the chat loop, followed by the plugins. \h is generated from this
+ * list, so a command only needs to be added here.
+ */
+fn builtin_commands() -> CommandRegistry {
+    let mut commands = CommandRegistry::new();
+    let builtins = vec![
+        Command {
+            name: "q",
+            aliases: &["quit", "exit"],
+            args: &[],
+            help: "Quit",
+            run: Box::new(|_, _| CommandResult::Quit),
+        },
+        Command {
+            name: "y",
+            aliases: &[],
+            args: &[ArgSpec::OptionalWord("N|all")],
+            help: "Yank the last code block, code block N, or all code blocks to the clipboard",
+            run: Box::new(|chat, args| {
+                yank_code_blocks(chat, &args[0]);
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "blocks",
+            aliases: &[],
+            args: &[],
+            help: "List code blocks",
+            run: Box::new(|chat, _| {
+                chat.print_code_blocks();
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "w",
+            aliases: &[],
+            args: &[ArgSpec::Number("N"), ArgSpec::OptionalText("file")],
+            help: "Write code block N to a file",
+            run: Box::new(|chat, args| {
+                if let Some((_, code_block)) = code_block_arg(chat, &args[0]) {
+                    let path = Some(args[1].as_str()).filter(|path| !path.is_empty());
+                    file_ops::write_code_block(&code_block, path);
+                }
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "o",
+            aliases: &[],
+            args: &[ArgSpec::Number("N")],
+            help: "Open code block N in $EDITOR",
+            run: Box::new(|chat, args| {
+                let (label, code_block) = match code_block_arg(chat, &args[0]) {
+                    Some(found) => found,
+                    None => return CommandResult::Done,
+                };
+                match file_ops::edit_code_block(&code_block, label) {
+                    Some(edited) => {
+                        if prompt::confirm("Send the modified version to the copilot?") {
+                            return CommandResult::Send(format!(
+                                "Here is my modified version of code block {}:\n```{}\n{}```",
+                                label, code_block.language_extension, edited
+                            ));
+                        }
+                    }
+                    None => println!("Code block {} was not modified", label),
+                }
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "run",
+            aliases: &[],
+            args: &[ArgSpec::Number("N")],
+            help: "Run code block N in a scratch directory",
+            run: Box::new(|chat, args| {
+                let (label, code_block) = match code_block_arg(chat, &args[0]) {
+                    Some(found) => found,
+                    None => return CommandResult::Done,
+                };
+                if let Some(result) = runner::run_code_block(&code_block, label) {
+                    if prompt::confirm("Send the output to the copilot?") {
+                        return CommandResult::Send(format!(
+                            "I ran code block {}, it finished with {}. The output was:\n```\n{}\n```",
+                            label,
+                            result.status,
+                            result.output_tail(8000).trim_end()
+                        ));
+                    }
+                }
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "apply",
+            aliases: &[],
+            args: &[ArgSpec::Number("N")],
+            help: "Apply the diff in code block N to the working tree",
+            run: Box::new(|chat, args| {
+                if let Some((_, code_block)) = code_block_arg(chat, &args[0]) {
+                    patch::apply_diff(&code_block.code);
+                }
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "edits",
+            aliases: &[],
+            args: &[ArgSpec::OptionalWord("prompt")],
+            help: "Apply the SEARCH/REPLACE edit blocks in the last answer, \\edits prompt asks \
+                   the model to answer with edit blocks",
+            run: Box::new(|chat, args| {
+                match args[0].as_str() {
+                    "prompt" => {
+                        chat.add_system_prompt(edit_block::EDIT_BLOCK_SYSTEM_PROMPT);
+                        println!("The model will now answer with SEARCH/REPLACE edit blocks");
+                    }
+                    "" => match chat.last_response() {
+                        Some(response) => {
+                            edit_block::apply_edit_blocks(&response);
+                        }
+                        None => println!("No answer to take edit blocks from"),
+                    },
+                    _ => println!("Usage: \\edits [prompt]"),
+                }
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "f",
+            aliases: &["file"],
+            args: &[ArgSpec::Text("path|glob")],
+            help: "Attach files to the next question",
+            run: Box::new(|chat, args| {
+                attach_files(chat, &args[0]);
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "diff",
+            aliases: &[],
+            args: &[ArgSpec::OptionalWord("range")],
+            help: "Attach the staged changes, or the changes in a range like main..HEAD",
+            run: Box::new(|chat, args| {
+                let context = match args[0].as_str() {
+                    "" => git::staged_diff(),
+                    range => git::range_diff(range),
+                };
+                attach_git_context(chat, context);
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "blame",
+            aliases: &[],
+            args: &[ArgSpec::Word("path:line")],
+            help: "Attach the blame and history of a line",
+            run: Box::new(|chat, args| {
+                attach_git_context(chat, git::blame(&args[0]));
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "p",
+            aliases: &[],
+            args: &[ArgSpec::OptionalText("question")],
+            help: "Send the clipboard contents, followed by the question. \\p anywhere in a \
+                   question is replaced by the clipboard contents too",
+            run: Box::new(|_, args| match get_clipboard_contents() {
+                Some(contents) => CommandResult::Send(format!("{} {}", contents, args[0])),
+                None => {
+                    println!("Error: Could not get clipboard contents");
+                    CommandResult::Done
+                }
+            }),
+        },
+        Command {
+            name: "d",
+            aliases: &[],
+            args: &[],
+            help: "Clear the current line",
+            run: Box::new(|_, _| {
+                print!("{}", clear::CurrentLine);
+                io::stdout().flush().unwrap();
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "cl",
+            aliases: &["clear"],
+            args: &[],
+            help: "Clear screen",
+            run: Box::new(|_, _| {
+                print!("\x1B[2J\x1B[1;1H");
+                io::stdout().flush().unwrap();
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "path",
+            aliases: &[],
+            args: &[],
+            help: "Show the turns leading to the current one",
+            run: Box::new(|chat, _| {
+                chat.print_path();
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "branches",
+            aliases: &[],
+            args: &[],
+            help: "List the tip of every branch",
+            run: Box::new(|chat, _| {
+                chat.print_branches();
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "checkout",
+            aliases: &[],
+            args: &[ArgSpec::TurnId("N")],
+            help: "Continue the conversation from turn N",
+            run: Box::new(|chat, args| {
+                chat.checkout(args[0].parse::<usize>().unwrap());
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "edit",
+            aliases: &[],
+            args: &[ArgSpec::TurnId("N"), ArgSpec::Text("question")],
+            help: "Ask again in place of question N, on a new branch",
+            run: Box::new(|chat, args| {
+                print_separator();
+                chat.edit(args[0].parse::<usize>().unwrap(), &args[1]);
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "save",
+            aliases: &[],
+            args: &[ArgSpec::Text("file")],
+            help: "Save the session to a file",
+            run: Box::new(|chat, args| {
+                if chat.save(&args[0]) {
+                    println!("Session saved to {}", args[0]);
+                }
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "load",
+            aliases: &[],
+            args: &[ArgSpec::Text("file")],
+            help: "Load a session from a file",
+            run: Box::new(|chat, args| {
+                if chat.load(&args[0]) {
+                    chat.print_path();
+                }
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "export",
+            aliases: &[],
+            args: &[ArgSpec::Text("file")],
+            help: "Export as .md, .html or .json",
+            run: Box::new(|chat, args| {
+                let path = &args[0];
+                match ExportFormat::from_path(path) {
+                    Some(format) => match fs::write(path, chat.export(&format)) {
+                        Ok(_) => println!("Conversation exported to {}", path),
+                        Err(e) => println!("Error: Could not write {}: {}", path, e),
+                    },
+                    None => println!("Usage: \\export <file.md|file.html|file.json>"),
+                }
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "model",
+            aliases: &[],
+            args: &[ArgSpec::OptionalWord("name")],
+            help: "Show or switch the model",
+            run: Box::new(|chat, args| {
+                if args[0].is_empty() {
+                    println!("Model: {}", chat.model());
+                } else {
+                    chat.set_model(&args[0]);
+                    println!("Switched to {}", args[0]);
+                }
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "persona",
+            aliases: &[],
+            args: &[ArgSpec::OptionalWord("name")],
+            help: "List personas, or use a persona's system prompt",
+            run: Box::new(|chat, args| {
+                let personas = &config::get().personas;
+                match personas.get(&args[0]) {
+                    Some(system) => {
+                        chat.add_system_prompt(system);
+                        println!("Using the {} persona", args[0]);
+                    }
+                    None => {
+                        if !args[0].is_empty() {
+                            println!("No persona named {}", args[0]);
+                        }
+                        let names: Vec<&str> = personas.keys().map(|name| name.as_str()).collect();
+                        println!("Personas: {}", names.join(", "));
+                    }
+                }
+                CommandResult::Done
+            }),
+        },
+        Command {
+            name: "themes",
+            aliases: &[],
+            args: &[ArgSpec::OptionalWord("name")],
+            help: "Preview the syntax highlighting themes, or one of them",
+            run: Box::new(|_, args| {
+                syntax::print_theme_previews(&args[0]);
+                CommandResult::Done
+            }),
+        },
+    ];
+    for command in builtins {
+        commands.register(command);
+    }
+    for (name, path) in plugin::find_plugins(&commands) {
+        commands.register(plugin::plugin_command(&name, path));
+    }
+    return commands;
+}
+
+/**
+ * Attach the output of a git command to the next question
+ */
+fn attach_git_context(chat: &mut Chat, context: Result<String, String>) {
+    match context {
+        Ok(context) => {
+            println!("Attached {} lines of git output", context.lines().count());
+            chat.attach(context);
+        }
+        Err(e) => println!("Error: {}", e),
+    }
 }
 
-fn main_loop(conversation_starter: Option<String>) {
+fn main_loop(
+    conversation_starter: Option<String>,
+    file_patterns: Vec<String>,
+    output_mode: OutputMode,
+) {
     let mut chat = Chat::new();
-    let mut input: String = String::new();
+    chat.set_output_mode(output_mode);
+    if output_mode != OutputMode::Pretty {
+        chat::send_chatter_to_stderr();
+    }
+    if let Some(persona) = &config::get().persona {
+        chat.add_system_prompt(persona);
+    }
+    for pattern in file_patterns {
+        attach_files(&mut chat, &pattern);
+    }
+    let commands = builtin_commands();
+    let mut editor = LineEditor::new();
+    let mut input: String;
+    let mut inserted: String = String::new(); // text a command put in the next prompt
 
     match conversation_starter {
         Some(msg) => {
@@ -36,65 +496,44 @@ fn main_loop(conversation_starter: Option<String>) {
         None => {}
     }
     loop {
-        input.clear();
-        print_prompt();
-        std::io::stdin().read_line(&mut input).unwrap();
+        // the user keeps typing after the inserted text
+        let complete = |line: &str| completion::complete(&commands, &chat, line);
+        input = match editor.read_line(">> ", &inserted, &complete) {
+            Some(line) => line,
+            None => break, // end of input
+        };
+        inserted.clear();
         input = input.trim().to_string();
         if input.is_empty() {
             continue;
         }
+        editor.add_history(&input);
 
         /* Handle special commands */
-        {
-            if input.starts_with("\\") && input.len() <= 3 {
-                match input.as_str() {
-                    "\\y" => {
-                        println!("Yanking is wip!"); //TODO: add back yanking
-                        continue;
-                    }
-                    "\\d" => {
-                        // delete line
-                        print!("{}", clear::CurrentLine);
-                        io::stdout().flush().unwrap();
-
-                        continue;
-                    }
-                    "\\p" => {
-                        // do nothing, this is handled later
-                    }
-                    "\\h" => {
-                        println!("Special commands:");
-                        println!("\\q - Quit");
-                        println!("\\h - Help");
-                        println!("\\y - Yank last code block to clipboard");
-                        println!("\\cl - Clear screen");
-                        print_separator();
-                        continue;
-                    }
-                    "\\cl" => {
-                        print!("\x1B[2J\x1B[1;1H");
-                        io::stdout().flush().unwrap();
-                        continue;
-                    }
-                    _ => {
-                        println!("Unknown comnad. Type \\h for help");
-                        print_separator();
-                        continue;
-                    }
+        if input.starts_with("\\") {
+            match commands.run(&mut chat, &input) {
+                CommandResult::Done => {
+                    print_separator();
+                    continue;
+                }
+                // sent as is: clipboard or plugin text may contain a \p of its own
+                CommandResult::Send(message) => input = message,
+                CommandResult::Insert(text) => {
+                    inserted = text;
+                    continue;
                 }
+                CommandResult::Quit => break,
             }
-        }
-
-        /* replace \p with clipboard contents */
-        if input.contains("\\p") {
-            let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-            match ctx.get_contents() {
-                Ok(msg) => {
+        } else if input.contains("\\p") {
+            /* replace \p with clipboard contents */
+            match get_clipboard_contents() {
+                Some(msg) => {
                     input = input.replace("\\p", &msg);
                 }
-                Err(_) => {
+                None => {
                     println!("Error: Could not get clipboard contents when trying to replace \\p with clipboard contents");
-                    return;
+                    print_separator();
+                    continue;
                 }
             }
         }
@@ -107,10 +546,90 @@ fn main_loop(conversation_starter: Option<String>) {
     }
 }
 
+/**
+ * Export a saved session without starting a chat
+ */
+fn export_session(session_path: &str, format: Option<&str>, output_path: Option<&str>) {
+    let format = match format {
+        Some(name) => ExportFormat::from_name(name),
+        None => output_path
+            .and_then(ExportFormat::from_path)
+            .or(Some(ExportFormat::Markdown)),
+    };
+    let format = match format {
+        Some(format) => format,
+        None => {
+            println!("Error: Unknown export format, use md, html or json");
+            return;
+        }
+    };
+
+    let (name, tree) = match chat::read_session_file(session_path) {
+        Some(session) => session,
+        None => return,
+    };
+    let exported = export::export(&name, &tree.history(), &format);
+    match output_path {
+        Some(path) => {
+            if let Err(e) = fs::write(path, exported) {
+                println!("Error: Could not write {}: {}", path, e);
+            }
+        }
+        None => print!("{}", exported),
+    }
+}
+
+/**
+ * Ask the copilot to review the staged changes
+ */
+fn review_staged_changes() {
+    match git::staged_diff() {
+        Ok(diff) => {
+            let mut chat = Chat::new();
+            chat.attach(diff);
+            chat.ask(git::REVIEW_PROMPT);
+        }
+        Err(e) => println!("Error: {}", e),
+    }
+}
+
+/**
+ * Print a commit message for the staged changes and nothing else, so that the output can be piped
+ * into `git commit -F -`. Errors go to stderr.
+ */
+fn print_commit_message() {
+    let diff = match git::staged_diff() {
+        Ok(diff) => diff,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let history = vec![
+        LLMMessage {
+            owner: LLMRole::System,
+            content: git::COMMIT_MESSAGE_PROMPT.to_string(),
+        },
+        LLMMessage {
+            owner: LLMRole::User,
+            content: diff,
+        },
+    ];
+    let mut copilot = CopilotChat::new();
+    match copilot.query(&history, |_| {}) {
+        Ok(message) => println!("{}", git::strip_code_fence(&message)),
+        Err(e) => {
+            eprintln!("Error: Could not generate a commit message: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
 fn main() {
     // test_syntax_highlighting();
     // return;
     let mut conversation_starter: Option<String> = None;
+    let mut piped_input_given = false;
 
     let matches = App::new("Copilot Chat CLI")
         .arg(
@@ -131,14 +650,169 @@ fn main() {
                 .takes_value(false)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("file")
+                .short("f")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("raw")
+                .long("raw")
+                .help("Print only the model's text, without highlighting or cursor movement")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .takes_value(true)
+                .possible_values(&["text", "json", "ndjson"])
+                .help("json prints one document per answer, ndjson streams one event per line")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .takes_value(true)
+                .help("Use a profile from the config file")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("model")
+                .long("model")
+                .takes_value(true)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("persona")
+                .long("persona")
+                .takes_value(true)
+                .help("Start with the system prompt of a persona from the config file")
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("theme")
+                .long("theme")
+                .takes_value(true)
+                .help("Highlight code with a theme, \\themes previews them")
+                .required(false),
+        )
         .arg(
             Arg::with_name("k")
                 .short("k")
                 .takes_value(true)
                 .required(false),
         )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export a saved session to markdown, html or json")
+                .arg(Arg::with_name("session").required(true))
+                .arg(
+                    Arg::with_name("format")
+                        .short("f")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["md", "html", "json"]),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(SubCommand::with_name("review").about("Review the staged changes"))
+        .subcommand(
+            SubCommand::with_name("commit-msg")
+                .about("Print a commit message for the staged changes, for git commit -F -"),
+        )
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Inspect the config")
+                .subcommand(SubCommand::with_name("show").about("Print the effective config")),
+        )
+        .subcommand(
+            SubCommand::with_name("batch")
+                .about("Run every prompt of a JSONL file, resuming where a previous run left off")
+                .arg(Arg::with_name("input").required(true))
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .takes_value(true)
+                        .help("Where results are appended, defaults to <input>.results.jsonl"),
+                )
+                .arg(
+                    Arg::with_name("concurrency")
+                        .short("j")
+                        .long("concurrency")
+                        .takes_value(true)
+                        .help("How many prompts run at once, defaults to 4"),
+                ),
+        )
         .get_matches();
 
+    let mut flags: Vec<(&str, &str)> = Vec::new();
+    for setting in ["model", "persona", "theme"] {
+        if let Some(value) = matches.value_of(setting) {
+            flags.push((setting, value));
+        }
+    }
+    match config::load(matches.value_of("profile"), &flags) {
+        Ok(config) => config::init(config),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+    }
+    terminal::init();
+
+    if let Some(config_matches) = matches.subcommand_matches("config") {
+        match config_matches.subcommand_name() {
+            Some("show") => config::print_config(config::get()),
+            _ => println!("{}", config_matches.usage()),
+        }
+        return;
+    }
+    if let Some(export_matches) = matches.subcommand_matches("export") {
+        export_session(
+            export_matches.value_of("session").unwrap(),
+            export_matches.value_of("format"),
+            export_matches.value_of("output"),
+        );
+        return;
+    }
+    if matches.subcommand_matches("review").is_some() {
+        review_staged_changes();
+        return;
+    }
+    if matches.subcommand_matches("commit-msg").is_some() {
+        print_commit_message();
+        return;
+    }
+    if let Some(batch_matches) = matches.subcommand_matches("batch") {
+        let input = batch_matches.value_of("input").unwrap();
+        let output = match batch_matches.value_of("output") {
+            Some(output) => output.to_string(),
+            None => batch::default_output_path(input),
+        };
+        let concurrency = match batch_matches
+            .value_of("concurrency")
+            .map(|n| n.parse::<usize>())
+        {
+            Some(Ok(n)) if n > 0 => n,
+            Some(_) => {
+                eprintln!("Error: --concurrency expects a positive number");
+                return;
+            }
+            None => 4,
+        };
+        batch::run_batch(input, &output, concurrency);
+        return;
+    }
+
     if matches.is_present("use_clipboard") {
         let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
         match ctx.get_contents() {
@@ -166,10 +840,47 @@ fn main() {
         }
     }
 
-    if matches.is_present("single_query") {
+    // piped input is context for the message, or the message itself
+    if !termion::is_tty(&io::stdin()) {
+        let mut piped_input = String::new();
+        if io::stdin().read_to_string(&mut piped_input).is_err() {
+            println!("Error: Could not read stdin");
+            return;
+        }
+        if !piped_input.trim().is_empty() {
+            conversation_starter = match conversation_starter {
+                Some(msg) => Some(format!("{}\n\n{}", piped_input.trim_end(), msg)),
+                None => Some(piped_input),
+            };
+            piped_input_given = true;
+        }
+    }
+
+    let output_mode = match matches.value_of("output") {
+        Some("json") => OutputMode::Json,
+        Some("ndjson") => OutputMode::Ndjson,
+        _ if matches.is_present("raw") || !termion::is_tty(&io::stdout()) => OutputMode::Raw,
+        _ => OutputMode::Pretty,
+    };
+    RAW_OUTPUT.store(output_mode != OutputMode::Pretty, Ordering::Relaxed);
+
+    let file_patterns: Vec<String> = match matches.values_of("file") {
+        Some(patterns) => patterns.map(|pattern| pattern.to_string()).collect(),
+        None => Vec::new(),
+    };
+
+    // stdin is used up by the piped input, so there is no way to continue the conversation
+    if matches.is_present("single_query") || piped_input_given {
         match conversation_starter {
             Some(msg) => {
                 let mut copilot = Chat::new();
+                copilot.set_output_mode(output_mode);
+                if let Some(persona) = &config::get().persona {
+                    copilot.add_system_prompt(persona);
+                }
+                for pattern in file_patterns {
+                    attach_files(&mut copilot, &pattern);
+                }
                 copilot.ask(&msg);
             }
             None => {
@@ -178,6 +889,6 @@ fn main() {
             }
         }
     } else {
-        main_loop(conversation_starter);
+        main_loop(conversation_starter, file_patterns, output_mode);
     }
 }
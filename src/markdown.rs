@@ -0,0 +1,883 @@
+use crate::config;
+use crate::terminal;
+use crate::width;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::OnceLock;
+use termion::{clear, cursor};
+use unicode_segmentation::UnicodeSegmentation;
+
+/**
+ * How a character of prose is drawn
+ */
+#[derive(Clone, Copy, PartialEq, Default)]
+struct Style {
+    bold: bool,
+    italic: bool,
+    strike: bool,
+    code: bool,    // `inline code`
+    link: bool,    // the text of a [link](url)
+    dim: bool,     // link targets, quote bars, rules and code fences
+    heading: bool, // # headings
+}
+
+impl Style {
+    /**
+     * The style of a character inside a block, the block's style(example: a heading's) merged in
+     */
+    fn merge(&self, block: Style) -> Style {
+        return Style {
+            bold: self.bold || block.bold,
+            italic: self.italic || block.italic,
+            strike: self.strike || block.strike,
+            code: self.code || block.code,
+            link: self.link || block.link,
+            dim: self.dim || block.dim,
+            heading: self.heading || block.heading,
+        };
+    }
+
+    /**
+     * The escape sequence switching from any style to this one
+     */
+    fn escape(&self) -> String {
+        let mut escape = String::from("\x1b[0m");
+        let codes = [
+            (self.bold || self.heading, "\x1b[1m"),
+            (self.dim, "\x1b[2m"),
+            (self.italic, "\x1b[3m"),
+            (self.link, "\x1b[4;34m"),
+            (self.strike, "\x1b[9m"),
+            (self.code, "\x1b[36m"),
+            (self.heading, "\x1b[35m"),
+        ];
+        for (on, code) in codes {
+            if on {
+                escape.push_str(code);
+            }
+        }
+        return escape;
+    }
+}
+
+/**
+ * A character drawn with a style. Characters are grapheme clusters, so that an e and its combining
+ * accent or an emoji and its skin tone are never split.
+ */
+#[derive(Clone)]
+struct StyledChar {
+    text: String,
+    style: Style,
+    source: usize, // offset of the markdown it was drawn from in its line, 0 for decorations
+    url: Option<Rc<str>>, // where the character links to, drawn as an OSC 8 hyperlink
+}
+
+type StyledText = Vec<StyledChar>;
+
+/**
+ * A line of prose, ready to be wrapped
+ */
+struct Block {
+    first_prefix: StyledText, // drawn before the first row(example: a list's bullet)
+    prefix: StyledText,       // drawn before the following rows, the hanging indent
+    text: StyledText,
+}
+
+enum Alignment {
+    Left,
+    Center,
+    Right,
+}
+
+/**
+ * Turns the prose of a response into formatted text as it streams in. A line is redrawn as its
+ * characters arrive, wrapped rows are printed for good once the line moved past them. Table rows
+ * are held back until the table ends, so that their columns can be aligned.
+ */
+pub struct MarkdownRenderer {
+    line: String,       // the markdown line being streamed
+    resume_at: usize,   // offset in the line of the row being redrawn, the rows before are printed
+    table: Vec<String>, // rows of the table being streamed
+    line_width: usize,  // usize::MAX to use the terminal's full width
+    drawn: usize,       // columns of the row being redrawn, as last drawn
+    drawn_at: usize,    // the terminal's width when that row was drawn
+    links: Links,       // where the words of the line link to, each word is looked up once
+}
+
+// words to the url they link to, None for words that aren't references
+type Links = HashMap<String, Option<Rc<str>>>;
+
+/**
+ * Columns the text takes in a terminal, wide characters(example: CJK, emoji) take 2
+ */
+fn text_width(text: &[StyledChar]) -> usize {
+    return text
+        .iter()
+        .map(|styled_char| width::grapheme_width(&styled_char.text))
+        .sum();
+}
+
+/**
+ * Style text that isn't part of the markdown, like bullets and borders
+ */
+fn styled(text: &str, style: Style) -> StyledText {
+    return styled_from(text, style, 0);
+}
+
+/**
+ * Style markdown found at `offset` in its line
+ */
+fn styled_from(text: &str, style: Style, offset: usize) -> StyledText {
+    return text
+        .grapheme_indices(true)
+        .map(|(i, grapheme)| StyledChar {
+            text: grapheme.to_string(),
+            style,
+            source: offset + i,
+            url: None,
+        })
+        .collect();
+}
+
+/**
+ * Parse the inline markdown of a line: **bold**, *italic*, ~~strike~~, `code` and [links](url).
+ * An emphasis that isn't closed yet applies to the rest of the line, so that text keeps its style
+ * while it streams in.
+ *
+ * @param offset: where the text starts in its line
+ */
+fn parse_inline(text: &str, offset: usize) -> StyledText {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut result: StyledText = Vec::new();
+    let mut style = Style::default();
+    let mut i = 0;
+    while i < chars.len() {
+        let (at, ch) = chars[i];
+        let prev = if i > 0 { Some(chars[i - 1].1) } else { None };
+        let run = chars[i..].iter().take_while(|(_, c)| *c == ch).count();
+        let next = chars.get(i + run).map(|(_, c)| *c);
+        match ch {
+            '\\' if next.is_some_and(|c| c.is_ascii_punctuation()) && run == 1 => {
+                result.extend(styled_from(
+                    &text[chars[i + 1].0..][..1],
+                    style,
+                    offset + at + 1,
+                ));
+                i += 2;
+            }
+            '`' => {
+                // the code runs until the same number of backticks, or the end of the line
+                let code_start = i + run;
+                let mut end = chars.len();
+                let mut j = code_start;
+                while j < chars.len() {
+                    let closing = chars[j..].iter().take_while(|(_, c)| *c == '`').count();
+                    if closing == run {
+                        end = j;
+                        break;
+                    }
+                    j += std::cmp::max(closing, 1);
+                }
+                let from = chars.get(code_start).map_or(text.len(), |(at, _)| *at);
+                let to = chars.get(end).map_or(text.len(), |(at, _)| *at);
+                let code_style = Style {
+                    code: true,
+                    ..style
+                };
+                result.extend(styled_from(&text[from..to], code_style, offset + from));
+                i = std::cmp::min(end + run, chars.len());
+            }
+            '*' | '_' | '~' => {
+                // _ inside a word(example: snake_case) is not emphasis
+                let intraword = ch == '_'
+                    && prev.is_some_and(|c| c.is_alphanumeric())
+                    && next.is_some_and(|c| c.is_alphanumeric());
+                let can_open = next.is_some_and(|c| !c.is_whitespace()) && !intraword;
+                let can_close = prev.is_some_and(|c| !c.is_whitespace()) && !intraword;
+                let toggle = |on: bool| (on && can_close) || (!on && can_open);
+                match (ch, run) {
+                    ('~', 2) if toggle(style.strike) => style.strike = !style.strike,
+                    ('*', 1) | ('_', 1) if toggle(style.italic) => style.italic = !style.italic,
+                    ('*', 2) | ('_', 2) if toggle(style.bold) => style.bold = !style.bold,
+                    ('*', 3) | ('_', 3) if toggle(style.bold) => {
+                        style.bold = !style.bold;
+                        style.italic = !style.italic;
+                    }
+                    _ => result.extend(styled_from(&text[at..at + run], style, offset + at)),
+                }
+                i += run;
+            }
+            '[' => {
+                let rest = &text[at..];
+                let link = rest.find("](").and_then(|middle| {
+                    let end = rest[middle..].find(')')? + middle;
+                    Some((&rest[1..middle], &rest[middle + 2..end], middle, end))
+                });
+                match link {
+                    Some((label, url, middle, end)) if !label.contains('[') => {
+                        let link_style = Style {
+                            link: true,
+                            ..style
+                        };
+                        let link: Rc<str> = Rc::from(url);
+                        for mut styled_char in parse_inline(label, offset + at + 1) {
+                            styled_char.style = styled_char.style.merge(link_style);
+                            styled_char.url = Some(link.clone());
+                            result.push(styled_char);
+                        }
+                        if url != label {
+                            let dim = Style { dim: true, ..style };
+                            let target = format!(" ({})", url);
+                            // the ( and ) around the url are drawn from the ]( and ) of the link
+                            for mut styled_char in styled_from(&target, dim, offset + at + middle) {
+                                styled_char.url = Some(link.clone());
+                                result.push(styled_char);
+                            }
+                        }
+                        i += rest[..end + 1].chars().count();
+                    }
+                    _ => {
+                        result.extend(styled_from("[", style, offset + at));
+                        i += 1;
+                    }
+                }
+            }
+            _ => {
+                // the text up to the next markdown character is styled at once, so that its
+                // graphemes aren't split
+                let plain = chars[i + 1..]
+                    .iter()
+                    .take_while(|(_, c)| !"\\`*_~[".contains(*c))
+                    .count();
+                let end = chars.get(i + 1 + plain).map_or(text.len(), |(at, _)| *at);
+                result.extend(styled_from(&text[at..end], style, offset + at));
+                i += 1 + plain;
+            }
+        }
+    }
+    return result;
+}
+
+/**
+ * Greedily wrap text at spaces. A word longer than the width is split.
+ *
+ * returns at least one row, even for empty text
+ */
+fn wrap(text: &[StyledChar], width: usize) -> Vec<StyledText> {
+    let width = std::cmp::max(width, 1);
+    let mut rows: Vec<StyledText> = vec![Vec::new()];
+    let mut word: StyledText = Vec::new();
+    let push_word = |rows: &mut Vec<StyledText>, word: &mut StyledText| {
+        let row_width = text_width(rows.last().unwrap());
+        if row_width > 0 && row_width + 1 + text_width(word) > width {
+            rows.push(Vec::new());
+        } else if row_width > 0 {
+            // the space takes the style around it, a link's underline doesn't stretch before it
+            let row = rows.last_mut().unwrap();
+            let (style, url) = match row.last() {
+                Some(before) if before.style == word[0].style && before.url == word[0].url => {
+                    (before.style, before.url.clone())
+                }
+                _ => (Style::default(), None),
+            };
+            row.push(StyledChar {
+                text: " ".to_string(),
+                style,
+                source: word[0].source,
+                url,
+            });
+        }
+        for styled_char in word.drain(..) {
+            let row_width = text_width(rows.last().unwrap());
+            if row_width > 0 && row_width + width::grapheme_width(&styled_char.text) > width {
+                rows.push(Vec::new());
+            }
+            rows.last_mut().unwrap().push(styled_char);
+        }
+    };
+    for styled_char in text {
+        if styled_char.text == " " {
+            if !word.is_empty() {
+                push_word(&mut rows, &mut word);
+            }
+        } else {
+            word.push(styled_char.clone());
+        }
+    }
+    if !word.is_empty() {
+        push_word(&mut rows, &mut word);
+    }
+    return rows;
+}
+
+/**
+ * Draw styled text, resetting the style at the end. Links are drawn as OSC 8 hyperlinks unless
+ * they're turned off in the config.
+ */
+fn to_ansi(text: &[StyledChar]) -> String {
+    let hyperlinks = config::get().hyperlinks;
+    let mut ansi = String::new();
+    let mut current = Style::default();
+    let mut current_url: Option<Rc<str>> = None;
+    for styled_char in text {
+        if styled_char.style != current {
+            ansi.push_str(&styled_char.style.escape());
+            current = styled_char.style;
+        }
+        if hyperlinks && styled_char.url != current_url {
+            let url = styled_char.url.as_deref().unwrap_or("");
+            ansi.push_str(&format!("\x1b]8;;{}\x1b\\", url));
+            current_url = styled_char.url.clone();
+        }
+        ansi.push_str(&styled_char.text);
+    }
+    if current_url.is_some() {
+        ansi.push_str("\x1b]8;;\x1b\\");
+    }
+    if current != Style::default() {
+        ansi.push_str("\x1b[0m");
+    }
+    return ansi;
+}
+
+/**
+ * The host name file:// links are made for
+ */
+fn host_name() -> &'static str {
+    static HOST_NAME: OnceLock<String> = OnceLock::new();
+    return HOST_NAME.get_or_init(|| {
+        let name = fs::read_to_string("/proc/sys/kernel/hostname")
+            .or_else(|_| fs::read_to_string("/etc/hostname"))
+            .unwrap_or_default();
+        name.trim().to_string()
+    });
+}
+
+/**
+ * A file:// url for a path, with the characters urls can't hold percent encoded
+ */
+fn file_url(path: &Path) -> String {
+    let mut url = format!("file://{}", host_name());
+    for byte in path.to_string_lossy().bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => {
+                url.push(byte as char)
+            }
+            _ => url.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    return url;
+}
+
+/**
+ * Where a word of prose links to: urls link to themselves, and paths of existing files(example:
+ * src/chat.rs:42, the line number is left out of the link) to the file
+ */
+fn reference_url(word: &str) -> Option<String> {
+    if word.starts_with("https://") || word.starts_with("http://") {
+        return Some(word.to_string());
+    }
+    // a path needs a directory or an extension, so that plain words aren't looked up
+    let path = word.split(':').next().unwrap_or("");
+    let file_name = path.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+    if !path.contains('/') && !file_name.trim_start_matches('.').contains('.') {
+        return None;
+    }
+    if !file_name.chars().any(|ch| ch.is_alphanumeric()) || !Path::new(path).exists() {
+        return None;
+    }
+    let absolute = env::current_dir().ok()?.join(path);
+    return Some(file_url(&absolute));
+}
+
+/**
+ * Link the urls and file references of a line's words, when they aren't in a link already
+ *
+ * @param links: the words looked up before, the words looked up now are added
+ * @param complete: the line ended, otherwise its last word may still grow and isn't looked up yet
+ */
+fn link_references(text: &mut StyledText, links: &mut Links, complete: bool) {
+    if !config::get().hyperlinks {
+        return;
+    }
+    let mut start = 0;
+    while start < text.len() {
+        let end = (start..text.len())
+            .find(|i| text[*i].text.trim().is_empty())
+            .unwrap_or(text.len());
+        let word: String = text[start..end].iter().map(|c| c.text.as_str()).collect();
+        // quotes, brackets and the punctuation ending a sentence aren't part of the reference
+        let leading = word.len() - word.trim_start_matches(['(', '[', '<', '"', '\'']).len();
+        let trimmed = word[leading..]
+            .trim_end_matches([')', ']', '>', '"', '\'', '.', ',', ';', ':', '!', '?']);
+        let already_linked = text[start..end].iter().any(|c| c.url.is_some());
+        if already_linked || (end == text.len() && !complete) {
+            start = end + 1;
+            continue;
+        }
+        let url = links
+            .entry(trimmed.to_string())
+            .or_insert_with(|| reference_url(trimmed).map(Rc::from));
+        if let Some(url) = url.clone() {
+            let from = start + word[..leading].graphemes(true).count();
+            let to = from + trimmed.graphemes(true).count();
+            for styled_char in text[from..to].iter_mut() {
+                styled_char.url = Some(url.clone());
+            }
+        }
+        start = end + 1;
+    }
+}
+
+/**
+ * A line made of 3 or more -, * or _ and spaces, all the same character
+ */
+fn is_rule(line: &str) -> bool {
+    let marks: Vec<char> = line.chars().filter(|ch| *ch != ' ').collect();
+    return marks.len() >= 3
+        && ['-', '*', '_'].contains(&marks[0])
+        && marks.iter().all(|ch| *ch == marks[0]);
+}
+
+/**
+ * The marker of a list item and the text after it(example: ("1.", "first") for "1. first")
+ */
+fn list_item(line: &str) -> Option<(String, &str)> {
+    let (marker, text) = line.split_once(' ')?;
+    return match marker {
+        "-" | "*" | "+" => Some(("•".to_string(), text)),
+        _ => {
+            let number = marker.strip_suffix('.').or(marker.strip_suffix(')'))?;
+            match !number.is_empty() && number.chars().all(|ch| ch.is_ascii_digit()) {
+                true => Some((marker.to_string(), text)),
+                false => None,
+            }
+        }
+    };
+}
+
+/**
+ * Parse the block markdown of a line: headings, list items, quotes, rules and code fences
+ *
+ * @param offset: where the text starts in its line
+ * @param first_prefix: drawn before the first row, quote bars and bullets are added to it
+ * @param prefix: drawn before the following rows
+ * @param width: the width rules are drawn to
+ */
+fn parse_block(
+    text: &str,
+    offset: usize,
+    first_prefix: StyledText,
+    prefix: StyledText,
+    width: usize,
+) -> Block {
+    let indent = text.len() - text.trim_start().len();
+    let trimmed = text.trim_start();
+    let offset = offset + indent;
+    let dim = Style {
+        dim: true,
+        ..Style::default()
+    };
+    let spaces = |count: usize| styled(&" ".repeat(count), Style::default());
+
+    if let Some(quoted) = trimmed.strip_prefix('>') {
+        let bar = styled("│ ", dim);
+        let mut first = first_prefix;
+        first.extend(bar.clone());
+        let mut rest = prefix;
+        rest.extend(bar);
+        return match quoted.strip_prefix(' ') {
+            Some(quoted) => parse_block(quoted, offset + 2, first, rest, width),
+            None => parse_block(quoted, offset + 1, first, rest, width),
+        };
+    }
+    if let Some((marker, item)) = list_item(trimmed).filter(|_| !is_rule(trimmed)) {
+        let mut first = first_prefix;
+        first.extend(spaces(indent));
+        first.extend(styled(&format!("{} ", marker), Style::default()));
+        let mut rest = prefix;
+        rest.extend(spaces(indent + width::display_width(&marker) + 1));
+        let item_offset = offset + trimmed.len() - item.len();
+        return parse_block(item, item_offset, first, rest, width);
+    }
+
+    let mut first = first_prefix;
+    first.extend(spaces(indent));
+    let mut rest = prefix;
+    rest.extend(spaces(indent));
+    let level = trimmed.chars().take_while(|ch| *ch == '#').count();
+    let text = if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+        // the fence of a code block, whose code is highlighted on its own
+        styled_from(trimmed, dim, offset)
+    } else if is_rule(trimmed) {
+        let rule = "─".repeat(width.saturating_sub(text_width(&rest)));
+        styled_from(&rule, dim, offset)
+    } else if (1..=6).contains(&level) && trimmed[level..].starts_with(' ') {
+        let mut heading = parse_inline(&trimmed[level + 1..], offset + level + 1);
+        for styled_char in heading.iter_mut() {
+            styled_char.style.heading = true;
+        }
+        heading
+    } else {
+        parse_inline(trimmed, offset)
+    };
+    return Block {
+        first_prefix: first,
+        prefix: rest,
+        text,
+    };
+}
+
+/**
+ * The cells of a table row, without the outer pipes
+ */
+fn table_cells(row: &str) -> Vec<String> {
+    let row = row.trim();
+    let row = row.strip_prefix('|').unwrap_or(row);
+    let row = row.strip_suffix('|').unwrap_or(row);
+    let mut cells: Vec<String> = vec![String::new()];
+    let mut escaped = false;
+    for ch in row.chars() {
+        match ch {
+            '|' if !escaped => cells.push(String::new()),
+            '\\' if !escaped => escaped = true,
+            _ => {
+                if escaped && ch != '|' {
+                    cells.last_mut().unwrap().push('\\');
+                }
+                escaped = false;
+                cells.last_mut().unwrap().push(ch);
+            }
+        }
+    }
+    return cells.iter().map(|cell| cell.trim().to_string()).collect();
+}
+
+/**
+ * The alignment of every column if the row is a table's header separator(example: |:--|:-:|--:|)
+ */
+fn table_alignments(row: &str) -> Option<Vec<Alignment>> {
+    let mut alignments: Vec<Alignment> = Vec::new();
+    for cell in table_cells(row) {
+        let dashes = cell.trim_start_matches(':').trim_end_matches(':');
+        if dashes.is_empty() || !dashes.chars().all(|ch| ch == '-') {
+            return None;
+        }
+        alignments.push(match (cell.starts_with(':'), cell.ends_with(':')) {
+            (true, true) => Alignment::Center,
+            (false, true) => Alignment::Right,
+            _ => Alignment::Left,
+        });
+    }
+    return Some(alignments);
+}
+
+/**
+ * Format table rows into a box, the columns shrunk and their cells wrapped when the table is wider
+ * than the width
+ */
+fn format_table(rows: &[String], width: usize) -> Vec<StyledText> {
+    let alignments = rows.get(1).and_then(|row| table_alignments(row));
+    let has_header = alignments.is_some();
+    let mut cells: Vec<Vec<StyledText>> = Vec::new();
+    for (i, row) in rows.iter().enumerate() {
+        if has_header && i == 1 {
+            continue;
+        }
+        let mut row_cells: Vec<StyledText> = Vec::new();
+        for cell in table_cells(row) {
+            let mut text = parse_inline(&cell, 0);
+            link_references(&mut text, &mut Links::new(), true);
+            if has_header && i == 0 {
+                for styled_char in text.iter_mut() {
+                    styled_char.style.bold = true;
+                }
+            }
+            row_cells.push(text);
+        }
+        cells.push(row_cells);
+    }
+    let alignments = alignments.unwrap_or_default();
+    let columns = cells.iter().map(|row| row.len()).max().unwrap_or(0);
+
+    let mut widths: Vec<usize> = vec![1; columns];
+    for row in &cells {
+        for (column, cell) in row.iter().enumerate() {
+            widths[column] = std::cmp::max(widths[column], text_width(cell));
+        }
+    }
+    // "│ " before every column and " │" at the end
+    let available = width.saturating_sub(3 * columns + 1);
+    while widths.iter().sum::<usize>() > available {
+        let widest = (0..columns).max_by_key(|column| widths[*column]).unwrap();
+        if widths[widest] <= 3 {
+            break;
+        }
+        widths[widest] -= 1;
+    }
+
+    let dim = Style {
+        dim: true,
+        ..Style::default()
+    };
+    let border = |left: &str, middle: &str, right: &str| {
+        let segments: Vec<String> = widths.iter().map(|w| "─".repeat(w + 2)).collect();
+        styled(&format!("{}{}{}", left, segments.join(middle), right), dim)
+    };
+    let mut lines: Vec<StyledText> = vec![border("┌", "┬", "┐")];
+    for (i, row) in cells.iter().enumerate() {
+        let wrapped: Vec<Vec<StyledText>> = (0..columns)
+            .map(|column| match row.get(column) {
+                Some(cell) => wrap(cell, widths[column]),
+                None => vec![Vec::new()],
+            })
+            .collect();
+        let height = wrapped.iter().map(|cell| cell.len()).max().unwrap_or(1);
+        for line_index in 0..height {
+            let mut line: StyledText = Vec::new();
+            for (column, cell) in wrapped.iter().enumerate() {
+                let text = cell.get(line_index).cloned().unwrap_or_default();
+                let padding = widths[column].saturating_sub(text_width(&text));
+                let (before, after) = match alignments.get(column) {
+                    Some(Alignment::Right) => (padding, 0),
+                    Some(Alignment::Center) => (padding / 2, padding - padding / 2),
+                    _ => (0, padding),
+                };
+                line.extend(styled("│ ", dim));
+                line.extend(styled(&" ".repeat(before), Style::default()));
+                line.extend(text);
+                line.extend(styled(&" ".repeat(after + 1), Style::default()));
+            }
+            line.extend(styled("│", dim));
+            lines.push(line);
+        }
+        if has_header && i == 0 && cells.len() > 1 {
+            lines.push(border("├", "┼", "┤"));
+        }
+    }
+    lines.push(border("└", "┴", "┘"));
+    return lines;
+}
+
+impl MarkdownRenderer {
+    pub fn new(line_width: usize) -> MarkdownRenderer {
+        return MarkdownRenderer {
+            line: String::new(),
+            resume_at: 0,
+            table: Vec::new(),
+            line_width,
+            drawn: 0,
+            drawn_at: 0,
+            links: Links::new(),
+        };
+    }
+
+    /**
+     * The configured line width, narrowed to the terminal's
+     */
+    fn width(&self) -> usize {
+        return std::cmp::min(terminal::width(), self.line_width);
+    }
+
+    /**
+     * Go back to the start of the row being redrawn and clear it. When the terminal got narrower
+     * since the row was drawn, the terminal may have reflowed it onto several rows: those are
+     * cleared too.
+     */
+    fn clear_row(&mut self) {
+        let terminal_width = terminal::width();
+        if self.drawn_at != terminal_width && self.drawn > terminal_width {
+            let rows = (self.drawn - 1) / terminal_width;
+            print!("{}", cursor::Up(rows as u16));
+        }
+        print!("\r{}", clear::AfterCursor);
+        self.drawn = 0;
+        self.drawn_at = terminal_width;
+    }
+
+    /**
+     * Redraw the rows of the current line that weren't printed for good. Rows are printed for good
+     * once the text moved past them, and never wrapped again: text that gets shorter when its
+     * markdown is complete(example: a link) doesn't move back up.
+     *
+     * @param done: the line ended, its last row is printed for good too
+     */
+    fn render_line(&mut self, done: bool) {
+        let width = self.width();
+        if self.line.trim_start().starts_with('|') {
+            // a table row is previewed on a single row, until the table is aligned
+            let preview = width::truncate(&self.line, width).to_string();
+            self.clear_row();
+            print!("\x1b[2m{}\x1b[0m", preview);
+            self.drawn = width::display_width(&preview);
+            io::stdout().flush().unwrap();
+            return;
+        }
+        let mut block = parse_block(&self.line, 0, Vec::new(), Vec::new(), width);
+        link_references(&mut block.text, &mut self.links, done);
+        let (first_prefix, text) = match self.resume_at {
+            0 => (block.first_prefix, block.text),
+            resume_at => {
+                let rest = block.text.into_iter().filter(|c| c.source >= resume_at);
+                (block.prefix.clone(), rest.collect())
+            }
+        };
+        let rows = wrap(&text, width.saturating_sub(text_width(&block.prefix)));
+        self.clear_row();
+        for (i, row) in rows.iter().enumerate() {
+            if i > 0 {
+                println!();
+            }
+            let prefix = if i == 0 { &first_prefix } else { &block.prefix };
+            print!("{}{}", to_ansi(prefix), to_ansi(row));
+            self.drawn = text_width(prefix) + text_width(row);
+        }
+        if rows.len() > 1 {
+            self.resume_at = match rows.last().unwrap().first() {
+                Some(first) => first.source,
+                None => self.line.len(),
+            };
+        }
+        if done {
+            println!();
+            self.resume_at = 0;
+            self.drawn = 0;
+            self.links.clear();
+        }
+        io::stdout().flush().unwrap();
+    }
+
+    /**
+     * Print the table held back, aligned
+     */
+    fn flush_table(&mut self) {
+        if self.table.is_empty() {
+            return;
+        }
+        self.clear_row();
+        for row in format_table(&self.table, self.width()) {
+            println!("{}", to_ansi(&row));
+        }
+        self.table.clear();
+    }
+
+    /**
+     * Add a character of prose
+     *
+     * returns the line once it ends
+     */
+    pub fn push(&mut self, ch: char) -> Option<String> {
+        if ch == '\n' {
+            let line = std::mem::take(&mut self.line);
+            if line.trim_start().starts_with('|') {
+                // held back until the table ends
+                self.table.push(line.clone());
+                self.clear_row();
+                self.resume_at = 0;
+                return Some(line);
+            }
+            self.flush_table();
+            self.line = line;
+            self.render_line(true);
+            return Some(std::mem::take(&mut self.line));
+        }
+        self.line.push(ch);
+        if !ch.is_whitespace() && self.line.trim_start().len() == ch.len_utf8() && ch != '|' {
+            self.flush_table();
+        }
+        self.render_line(false);
+        return None;
+    }
+
+    /**
+     * Print the line as it is without ending it(example: the ``` line of a code block, whose new
+     * line is printed with the code)
+     */
+    pub fn end_line(&mut self) {
+        self.flush_table();
+        self.render_line(false);
+        self.line.clear();
+        self.resume_at = 0;
+        self.drawn = 0;
+        self.links.clear();
+    }
+
+    /**
+     * Print whatever is held back once the response is complete
+     */
+    pub fn finish(&mut self) {
+        if !self.line.is_empty() || !self.table.is_empty() {
+            self.end_line();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrap_text(text: &str, width: usize) -> Vec<String> {
+        let rows = wrap(&styled(text, Style::default()), width);
+        let rows: Vec<String> = rows
+            .iter()
+            .map(|row| row.iter().map(|c| c.text.as_str()).collect())
+            .collect();
+        for row in &rows {
+            assert!(
+                width::display_width(row) <= width,
+                "{:?} is wider than {}",
+                row,
+                width
+            );
+        }
+        return rows;
+    }
+
+    #[test]
+    fn wrap_counts_wide_characters_as_two_columns() {
+        assert_eq!(wrap_text("ab 中文 cd", 5), ["ab", "中文", "cd"]);
+        assert_eq!(wrap_text("ab 中文 cd", 7), ["ab 中文", "cd"]);
+        assert_eq!(wrap_text("a中文", 4), ["a中", "文"]);
+        assert_eq!(wrap_text("中文中文中", 5), ["中文", "中文", "中"]);
+    }
+
+    #[test]
+    fn wrap_keeps_graphemes_whole() {
+        let family = "👨\u{200d}👩\u{200d}👧";
+        assert_eq!(
+            wrap_text(&format!("ab {} cd", family), 5),
+            [format!("ab {}", family), "cd".to_string()]
+        );
+        assert_eq!(
+            wrap_text("cafe\u{301} ol\u{301}e\u{301}", 4),
+            ["cafe\u{301}", "ol\u{301}e\u{301}"]
+        );
+        assert_eq!(
+            wrap_text(&format!("{}{}{}", family, family, family), 5),
+            [format!("{}{}", family, family), family.to_string()]
+        );
+    }
+
+    #[test]
+    fn wrap_mixed_prose_at_the_width() {
+        let text = "Rust は安全で速い language です, café and 👍🏽 emoji: 日本語のテキストと English words mixed";
+        // no word is longer than 18 columns, so rows are only broken at spaces
+        for width in 18..100 {
+            assert_eq!(wrap_text(text, width).join(" "), text);
+        }
+        assert_eq!(
+            wrap_text(text, 20),
+            [
+                "Rust は安全で速い",
+                "language です, café",
+                "and 👍🏽 emoji:",
+                "日本語のテキストと",
+                "English words mixed",
+            ]
+        );
+    }
+}
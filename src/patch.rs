@@ -0,0 +1,409 @@
+use crate::file_ops::{self, FileChange};
+use std::fs;
+use std::path::Path;
+
+enum HunkLine {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+struct Hunk {
+    header: String,
+    old_start: usize, // 1-based line number the hunk claims to start at, 0 if unknown
+    lines: Vec<HunkLine>,
+}
+
+impl Hunk {
+    // the lines the hunk expects to find in the file
+    fn old_lines(&self) -> Vec<&str> {
+        return self
+            .lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(text) | HunkLine::Remove(text) => Some(text.as_str()),
+                HunkLine::Add(_) => None,
+            })
+            .collect();
+    }
+
+    // the lines replacing them
+    fn new_lines(&self) -> Vec<&str> {
+        return self
+            .lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(text) | HunkLine::Add(text) => Some(text.as_str()),
+                HunkLine::Remove(_) => None,
+            })
+            .collect();
+    }
+
+    // number of unchanged lines at the start and at the end of the hunk
+    fn context_len(&self) -> (usize, usize) {
+        let is_context = |line: &&HunkLine| matches!(line, HunkLine::Context(_));
+        let leading = self.lines.iter().take_while(is_context).count();
+        let trailing = self.lines.iter().rev().take_while(is_context).count();
+        if leading == self.lines.len() {
+            return (leading, 0);
+        }
+        return (leading, trailing);
+    }
+}
+
+struct FilePatch {
+    old_path: Option<String>, // None for a new file
+    new_path: Option<String>, // None for a deleted file
+    hunks: Vec<Hunk>,
+}
+
+impl FilePatch {
+    fn path(&self) -> &str {
+        return self
+            .new_path
+            .as_deref()
+            .or(self.old_path.as_deref())
+            .unwrap_or("");
+    }
+}
+
+/**
+ * Parse the path out of a ---/+++ line, None for /dev/null
+ */
+fn parse_path(line: &str) -> Option<String> {
+    // drop the timestamp some tools append after a tab
+    let path = line[4..].split('\t').next().unwrap().trim();
+    if path == "/dev/null" {
+        return None;
+    }
+    let path = path
+        .strip_prefix("a/")
+        .or(path.strip_prefix("b/"))
+        .unwrap_or(path);
+    return Some(path.to_string());
+}
+
+/**
+ * Parse the old start line out of a hunk header(example: "@@ -12,7 +12,8 @@ fn main()")
+ * models often leave out the numbers, in which case 0 is returned
+ */
+fn parse_hunk_start(header: &str) -> usize {
+    return header
+        .split_whitespace()
+        .find(|word| word.starts_with('-'))
+        .and_then(|word| word[1..].split(',').next()?.parse::<usize>().ok())
+        .unwrap_or(0);
+}
+
+/**
+ * Parse a unified diff into per-file patches. Line counts in hunk headers are ignored since
+ * models get them wrong more often than not, a hunk ends at the next header.
+ */
+fn parse_unified_diff(text: &str) -> Result<Vec<FilePatch>, String> {
+    let mut patches: Vec<FilePatch> = Vec::new();
+    let lines: Vec<&str> = text.lines().collect();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        if line.starts_with("--- ") && i + 1 < lines.len() && lines[i + 1].starts_with("+++ ") {
+            patches.push(FilePatch {
+                old_path: parse_path(line),
+                new_path: parse_path(lines[i + 1]),
+                hunks: Vec::new(),
+            });
+            i += 2;
+            continue;
+        }
+        if line.starts_with("@@") {
+            let patch = match patches.last_mut() {
+                Some(patch) => patch,
+                None => return Err(format!("hunk \"{}\" comes before any file header", line)),
+            };
+            patch.hunks.push(Hunk {
+                header: line.to_string(),
+                old_start: parse_hunk_start(line),
+                lines: Vec::new(),
+            });
+            i += 1;
+            continue;
+        }
+        if let Some(hunk) = patches.last_mut().and_then(|patch| patch.hunks.last_mut()) {
+            if let Some(text) = line.strip_prefix('+') {
+                hunk.lines.push(HunkLine::Add(text.to_string()));
+            } else if let Some(text) = line.strip_prefix('-') {
+                hunk.lines.push(HunkLine::Remove(text.to_string()));
+            } else if let Some(text) = line.strip_prefix(' ') {
+                hunk.lines.push(HunkLine::Context(text.to_string()));
+            } else if line.is_empty() {
+                // editors and models like to strip the space of empty context lines
+                hunk.lines.push(HunkLine::Context(String::new()));
+            }
+            // anything else("\ No newline at end of file", "diff --git", "index ...") is ignored
+        }
+        i += 1;
+    }
+
+    if patches.is_empty() {
+        return Err("no file headers(---/+++) found".to_string());
+    }
+    for patch in &mut patches {
+        // trailing empty lines are usually padding after the diff, not context
+        for hunk in &mut patch.hunks {
+            while let Some(HunkLine::Context(text)) = hunk.lines.last() {
+                if !text.is_empty() {
+                    break;
+                }
+                hunk.lines.pop();
+            }
+        }
+    }
+    return Ok(patches);
+}
+
+fn normalize_whitespace(line: &str) -> String {
+    return line.split_whitespace().collect::<Vec<&str>>().join(" ");
+}
+
+/**
+ * Find where `needle` occurs in `haystack`, at or after `min_start`, preferring the position
+ * closest to `hint`. Tries an exact match first, then ignores whitespace differences. Edit blocks
+ * are matched with it too.
+ */
+pub fn find_lines(
+    haystack: &[String],
+    needle: &[&str],
+    min_start: usize,
+    hint: usize,
+) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(std::cmp::max(
+            min_start,
+            std::cmp::min(hint, haystack.len()),
+        ));
+    }
+    if haystack.len() < needle.len() {
+        return None;
+    }
+    let last_start = haystack.len() - needle.len();
+    if min_start > last_start {
+        return None;
+    }
+
+    // candidate positions ordered by distance from the hint
+    let mut positions: Vec<usize> = (min_start..=last_start).collect();
+    positions.sort_by_key(|pos| (*pos as i64 - hint as i64).abs());
+
+    let exact = |pos: &usize| {
+        needle
+            .iter()
+            .enumerate()
+            .all(|(i, line)| haystack[pos + i] == *line)
+    };
+    if let Some(pos) = positions.iter().find(|pos| exact(pos)) {
+        return Some(*pos);
+    }
+    let fuzzy = |pos: &usize| {
+        needle
+            .iter()
+            .enumerate()
+            .all(|(i, line)| normalize_whitespace(&haystack[pos + i]) == normalize_whitespace(line))
+    };
+    return positions.iter().find(|pos| fuzzy(pos)).copied();
+}
+
+/**
+ * Apply a file's hunks to its content, see `join_lines` for its line endings
+ * returns the new content, or the reason of every hunk that couldn't be applied
+ */
+fn apply_hunks(content: &str, hunks: &[Hunk]) -> Result<String, Vec<String>> {
+    let mut lines: Vec<String> = content.lines().map(|line| line.to_string()).collect();
+    let mut rejections: Vec<String> = Vec::new();
+    let mut min_start = 0; // hunks apply in order, each one after the previous one
+    let mut offset: i64 = 0; // how far the file moved compared to the hunk headers
+
+    for (i, hunk) in hunks.iter().enumerate() {
+        let old_lines = hunk.old_lines();
+        let new_lines = hunk.new_lines();
+        let hint = std::cmp::max(hunk.old_start as i64 - 1 + offset, 0) as usize;
+
+        // like patch's fuzz factor, drop up to 2 lines of context at each end if the hunk
+        // doesn't match with its full context
+        let (leading, trailing) = hunk.context_len();
+        let mut found: Option<(usize, usize, usize)> = None;
+        for fuzz in 0..=2 {
+            let skip_begin = std::cmp::min(fuzz, leading);
+            let skip_end = std::cmp::min(fuzz, trailing);
+            if fuzz > 0 && skip_begin + skip_end == 0 {
+                break;
+            }
+            let needle = &old_lines[skip_begin..old_lines.len() - skip_end];
+            if let Some(pos) = find_lines(&lines, needle, min_start, hint + skip_begin) {
+                found = Some((pos, skip_begin, skip_end));
+                break;
+            }
+        }
+
+        match found {
+            Some((pos, skip_begin, skip_end)) => {
+                let removed = old_lines.len() - skip_begin - skip_end;
+                let replacement: Vec<String> = new_lines[skip_begin..new_lines.len() - skip_end]
+                    .iter()
+                    .map(|line| line.to_string())
+                    .collect();
+                let added = replacement.len();
+                lines.splice(pos..pos + removed, replacement);
+                min_start = pos + added;
+                offset = pos as i64 - skip_begin as i64 - (hunk.old_start as i64 - 1)
+                    + added as i64
+                    - removed as i64;
+            }
+            None => {
+                rejections.push(format!(
+                    "hunk #{} ({}): context not found in the file",
+                    i + 1,
+                    hunk.header
+                ));
+            }
+        }
+    }
+
+    if !rejections.is_empty() {
+        return Err(rejections);
+    }
+    return Ok(join_lines(&lines, content));
+}
+
+/**
+ * Join the changed lines of a file, keeping the line endings(\n or \r\n) of its content and its
+ * lack of a new line at the end. New files end with a new line.
+ */
+pub fn join_lines(lines: &[String], content: &str) -> String {
+    let newline = if content.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let mut new_content = lines.join(newline);
+    if !new_content.is_empty() && (content.is_empty() || content.ends_with('\n')) {
+        new_content.push_str(newline);
+    }
+    return new_content;
+}
+
+/**
+ * The content a file has once the changes so far are made: Some(None) if they delete it, None if
+ * they don't touch it
+ */
+fn pending_content(changes: &[FileChange], path: &str) -> Option<Option<String>> {
+    return changes
+        .iter()
+        .find(|change| change.path == path)
+        .map(|change| change.new.clone());
+}
+
+/**
+ * Whether a file exists once the changes so far are made
+ */
+fn will_exist(changes: &[FileChange], path: &str) -> bool {
+    match pending_content(changes, path) {
+        Some(content) => return content.is_some(),
+        None => return Path::new(path).exists(),
+    }
+}
+
+/**
+ * Record the new content of a file, replacing what an earlier patch of the diff made of it
+ */
+fn record_change(changes: &mut Vec<FileChange>, path: &str, new: Option<String>) {
+    match changes.iter_mut().find(|change| change.path == path) {
+        Some(change) => change.new = new,
+        None => changes.push(FileChange {
+            path: path.to_string(),
+            old: fs::read_to_string(path).ok(),
+            new,
+        }),
+    }
+}
+
+/**
+ * Apply a unified diff to the working tree. Every hunk is validated against the current files
+ * first, the changes are previewed and only written after confirmation. If any hunk is rejected
+ * nothing is written. Patches apply in order, a file patched twice gets both patches, and a
+ * renamed file is deleted from its old path.
+ *
+ * returns true if the diff was applied
+ */
+pub fn apply_diff(diff_text: &str) -> bool {
+    let patches = match parse_unified_diff(diff_text) {
+        Ok(patches) => patches,
+        Err(e) => {
+            println!("Error: Not a unified diff: {}", e);
+            return false;
+        }
+    };
+
+    let mut changes: Vec<FileChange> = Vec::new();
+    let mut rejections: Vec<String> = Vec::new();
+    for patch in &patches {
+        let path = patch.path().to_string();
+        // what an earlier patch made of the file, or the file on disk
+        let old = match &patch.old_path {
+            Some(old_path) => match pending_content(&changes, old_path) {
+                Some(Some(content)) => Some(content),
+                Some(None) => {
+                    rejections.push(format!("{}: an earlier patch deletes it", old_path));
+                    continue;
+                }
+                None => match fs::read_to_string(old_path) {
+                    Ok(content) => Some(content),
+                    Err(e) => {
+                        rejections.push(format!("{}: could not read the file: {}", old_path, e));
+                        continue;
+                    }
+                },
+            },
+            None => None,
+        };
+        // a created or renamed file must not replace another one
+        if patch.old_path.as_deref() != Some(path.as_str()) && will_exist(&changes, &path) {
+            rejections.push(format!(
+                "{}: the diff creates it but it already exists",
+                path
+            ));
+            continue;
+        }
+        let new = match &patch.new_path {
+            Some(_) => match apply_hunks(old.as_deref().unwrap_or(""), &patch.hunks) {
+                Ok(new) => Some(new),
+                Err(reasons) => {
+                    for reason in reasons {
+                        rejections.push(format!("{}: {}", path, reason));
+                    }
+                    continue;
+                }
+            },
+            None => None,
+        };
+        record_change(&mut changes, &path, new);
+        if let Some(old_path) = &patch.old_path {
+            if *old_path != path {
+                record_change(&mut changes, old_path, None);
+            }
+        }
+    }
+    // a file created then deleted by the diff is left alone
+    changes.retain(|change| change.old.is_some() || change.new.is_some());
+
+    if !rejections.is_empty() {
+        println!("The diff does not apply, nothing was changed:");
+        for rejection in rejections {
+            println!("  {}", rejection);
+        }
+        return false;
+    }
+    if !file_ops::confirm_paths_outside_current_dir(&changes) {
+        return false;
+    }
+
+    return file_ops::apply_changes(&changes);
+}
@@ -0,0 +1,234 @@
+use crate::chat::Chat;
+use crate::command_registry::{ArgSpec, Command, CommandRegistry, CommandResult};
+use crate::config;
+use crate::prompt;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command as Process, Stdio};
+
+const PROJECT_COMMANDS_DIR: &str = ".copilot-x-cli/commands";
+
+/**
+ * The help of a plugin is taken from a "help:" comment in its first lines(example: "# help: Open
+ * the ticket mentioned in the last answer")
+ */
+fn read_help(path: &Path) -> Option<String> {
+    let content = fs::read(path).ok()?;
+    let head = String::from_utf8_lossy(&content[..std::cmp::min(content.len(), 2048)]).to_string();
+    return head.lines().take(10).find_map(|line| {
+        let (_, help) = line.split_once("help:")?;
+        Some(help.trim().to_string())
+    });
+}
+
+/**
+ * Every executable of a directory
+ *
+ * returns (command name, path) pairs, the name being the file name without its extension
+ */
+fn find_executables(dir: &Path) -> Vec<(String, PathBuf)> {
+    let mut entries: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .collect(),
+        Err(_) => return Vec::new(),
+    };
+    entries.sort();
+    let mut executables = Vec::new();
+    for path in entries {
+        let executable = fs::metadata(&path)
+            .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false);
+        match path.file_stem().and_then(|stem| stem.to_str()) {
+            Some(name) if executable && !name.starts_with('.') && !name.contains(' ') => {
+                executables.push((name.to_string(), path.clone()))
+            }
+            _ => continue,
+        }
+    }
+    return executables;
+}
+
+/**
+ * $XDG_DATA_HOME/copilot-x-cli/trusted_dirs: the directories whose plugins may be loaded, one per
+ * line
+ */
+fn trusted_dirs_file() -> Option<PathBuf> {
+    let mut path = config::data_dir()?;
+    path.push("trusted_dirs");
+    return Some(path);
+}
+
+/**
+ * Whether the plugins of the current directory may be loaded. They run with the user's rights and
+ * come with whatever repository was cloned, so they're only loaded once the user trusted the
+ * directory, which is asked the first time and remembered.
+ *
+ * @param names: the project's plugins, shown when asking
+ */
+fn is_current_dir_trusted(names: &[&str]) -> bool {
+    let dir = match env::current_dir().and_then(|dir| dir.canonicalize()) {
+        Ok(dir) => dir.to_string_lossy().to_string(),
+        Err(_) => return false,
+    };
+    let trusted_dirs_file = trusted_dirs_file();
+    if let Some(path) = &trusted_dirs_file {
+        let trusted_dirs = fs::read_to_string(path).unwrap_or_default();
+        if trusted_dirs.lines().any(|trusted_dir| trusted_dir == dir) {
+            return true;
+        }
+    }
+
+    println!(
+        "{} has plugins that would run as you: \\{}",
+        PROJECT_COMMANDS_DIR,
+        names.join(", \\")
+    );
+    if !prompt::confirm(&format!("Trust {} and load them?", dir)) {
+        println!("Skipped the project's plugins");
+        return false;
+    }
+    if let Some(path) = trusted_dirs_file {
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        let saved = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{}", dir));
+        if let Err(e) = saved {
+            println!("Error: Could not save {}: {}", path.display(), e);
+        }
+    }
+    return true;
+}
+
+/**
+ * The plugins whose name isn't taken yet, by a command or a plugin found earlier
+ */
+fn untaken_plugins(
+    found: Vec<(String, PathBuf)>,
+    commands: &CommandRegistry,
+    earlier: &[(String, PathBuf)],
+) -> Vec<(String, PathBuf)> {
+    let mut plugins = Vec::new();
+    for (name, path) in found {
+        if commands.is_taken(&name) || earlier.iter().any(|(other, _)| *other == name) {
+            println!(
+                "Skipped plugin {}: \\{} already exists",
+                path.display(),
+                name
+            );
+            continue;
+        }
+        plugins.push((name, path));
+    }
+    return plugins;
+}
+
+/**
+ * Find the plugins: the user's, next to the config file, then the project's, in the current
+ * directory, if the user trusts it. The builtins win over the plugins, and the user's plugins over
+ * the project's.
+ *
+ * returns (command name, path) pairs
+ */
+pub fn find_plugins(commands: &CommandRegistry) -> Vec<(String, PathBuf)> {
+    let mut user_dir = config::config_path();
+    user_dir.set_file_name("commands");
+    let mut plugins = untaken_plugins(find_executables(&user_dir), commands, &[]);
+
+    let project_dir = Path::new(PROJECT_COMMANDS_DIR);
+    let project_plugins = untaken_plugins(find_executables(project_dir), commands, &plugins);
+    if !project_plugins.is_empty() {
+        let names: Vec<&str> = project_plugins
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        if is_current_dir_trusted(&names) {
+            plugins.extend(project_plugins);
+        }
+    }
+    return plugins;
+}
+
+/**
+ * Run a plugin with the session on stdin: the chat's context_json() plus "args", the text after the
+ * command, and "argv", that text split on whitespace. The plugin answers on stdout with a json
+ * object holding one of "insert"(text put in the next prompt), "send"(a message sent to the
+ * copilot) or "print"(text shown as is). Output that isn't json is printed.
+ */
+fn run_plugin(path: &Path, chat: &Chat, args: &str) -> CommandResult {
+    let argv: Vec<&str> = args.split_whitespace().collect();
+    let mut session = chat.context_json();
+    session["args"] = serde_json::json!(args);
+    session["argv"] = serde_json::json!(argv);
+
+    let mut child = match Process::new(path)
+        .args(&argv)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            println!("Error: Could not run {}: {}", path.display(), e);
+            return CommandResult::Done;
+        }
+    };
+    // a plugin that doesn't read its stdin closes the pipe early, which is fine
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(session.to_string().as_bytes());
+    }
+    let output = match child.wait_with_output() {
+        Ok(output) => output,
+        Err(e) => {
+            println!("Error: Could not run {}: {}", path.display(), e);
+            return CommandResult::Done;
+        }
+    };
+    if !output.status.success() {
+        println!("Error: {} failed with {}", path.display(), output.status);
+        return CommandResult::Done;
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    let reply: serde_json::Value = match serde_json::from_str(&stdout) {
+        Ok(reply) => reply,
+        Err(_) => {
+            print!("{}", stdout);
+            return CommandResult::Done;
+        }
+    };
+    if let Some(text) = reply["insert"].as_str() {
+        return CommandResult::Insert(text.to_string());
+    }
+    if let Some(message) = reply["send"].as_str() {
+        return CommandResult::Send(message.to_string());
+    }
+    match reply["print"].as_str() {
+        Some(text) => println!("{}", text.trim_end()),
+        None => println!("{}", stdout.trim_end()),
+    }
+    return CommandResult::Done;
+}
+
+/**
+ * Make a command running a plugin
+ */
+pub fn plugin_command(name: &str, path: PathBuf) -> Command {
+    let help = read_help(&path).unwrap_or_else(|| format!("Run {}", path.display()));
+    // commands live as long as the program, leaking their name and help keeps Command simple
+    return Command {
+        name: Box::leak(name.to_string().into_boxed_str()),
+        aliases: &[],
+        args: &[ArgSpec::OptionalText("args")],
+        help: Box::leak(help.into_boxed_str()),
+        run: Box::new(move |chat, args| run_plugin(&path, chat, &args[0])),
+    };
+}
@@ -0,0 +1,15 @@
+use std::io::{self, Write};
+
+/**
+ * Ask a yes/no question on the terminal, anything but y/yes counts as no
+ */
+pub fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+    let answer = answer.trim().to_lowercase();
+    return answer == "y" || answer == "yes";
+}
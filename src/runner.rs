@@ -0,0 +1,362 @@
+use crate::codeblock_builder::CodeBlock;
+use crate::config;
+use crate::prompt;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const CODE: &str = "{code}"; // placeholder for the code block in a run config's files
+
+const CARGO_TOML: &str = "[package]
+name = \"scratch\"
+version = \"0.1.0\"
+edition = \"2021\"
+
+[dependencies]
+";
+
+// timeout of languages that only the config knows how to run
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/**
+ * How to run code of a language: the files to create in the scratch directory(the code block
+ * goes wherever CODE is), and the command to run there
+ */
+struct RunConfig {
+    files: Vec<(String, &'static str)>,
+    command: Vec<String>,
+    timeout_secs: u64,
+}
+
+/**
+ * The built-in way to run a language, [run.<extension>] tables in the config change it
+ */
+struct DefaultRunConfig {
+    language_extension: &'static str,
+    files: &'static [(&'static str, &'static str)],
+    command: &'static [&'static str],
+    timeout_secs: u64,
+}
+
+const RUN_CONFIGS: [DefaultRunConfig; 11] = [
+    DefaultRunConfig {
+        language_extension: "sh",
+        files: &[("script.sh", CODE)],
+        command: &["bash", "script.sh"],
+        timeout_secs: 30,
+    },
+    DefaultRunConfig {
+        language_extension: "zsh",
+        files: &[("script.zsh", CODE)],
+        command: &["zsh", "script.zsh"],
+        timeout_secs: 30,
+    },
+    DefaultRunConfig {
+        language_extension: "py",
+        files: &[("main.py", CODE)],
+        command: &["python3", "main.py"],
+        timeout_secs: 30,
+    },
+    DefaultRunConfig {
+        language_extension: "js",
+        files: &[("main.js", CODE)],
+        command: &["node", "main.js"],
+        timeout_secs: 30,
+    },
+    DefaultRunConfig {
+        language_extension: "rb",
+        files: &[("main.rb", CODE)],
+        command: &["ruby", "main.rb"],
+        timeout_secs: 30,
+    },
+    DefaultRunConfig {
+        language_extension: "pl",
+        files: &[("main.pl", CODE)],
+        command: &["perl", "main.pl"],
+        timeout_secs: 30,
+    },
+    DefaultRunConfig {
+        language_extension: "lua",
+        files: &[("main.lua", CODE)],
+        command: &["lua", "main.lua"],
+        timeout_secs: 30,
+    },
+    DefaultRunConfig {
+        language_extension: "go",
+        files: &[("main.go", CODE)],
+        command: &["go", "run", "main.go"],
+        timeout_secs: 120,
+    },
+    DefaultRunConfig {
+        language_extension: "rs",
+        files: &[("Cargo.toml", CARGO_TOML), ("src/main.rs", CODE)],
+        command: &["cargo", "run", "--quiet"],
+        timeout_secs: 120,
+    },
+    DefaultRunConfig {
+        language_extension: "c",
+        files: &[("main.c", CODE)],
+        command: &["sh", "-c", "cc main.c -o main && ./main"],
+        timeout_secs: 60,
+    },
+    DefaultRunConfig {
+        language_extension: "cpp",
+        files: &[("main.cpp", CODE)],
+        command: &["sh", "-c", "c++ main.cpp -o main && ./main"],
+        timeout_secs: 60,
+    },
+];
+
+pub struct RunResult {
+    pub status: String, // exit code, signal, or timeout
+    pub output: String, // stdout followed by stderr
+}
+
+impl RunResult {
+    /**
+     * The last `max_chars` characters of the output, which is where errors usually are
+     */
+    pub fn output_tail(&self, max_chars: usize) -> &str {
+        let len = self.output.chars().count();
+        if len <= max_chars {
+            return &self.output;
+        }
+        let (begin, _) = self.output.char_indices().nth(len - max_chars).unwrap();
+        return &self.output[begin..];
+    }
+}
+
+/**
+ * Create a fresh scratch directory in the system's temp dir
+ */
+fn create_scratch_dir(label: usize) -> Option<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis())
+        .unwrap_or(0);
+    let mut dir = env::temp_dir();
+    dir.push(format!(
+        "copilot-run-{}-{}-{}",
+        std::process::id(),
+        label,
+        timestamp
+    ));
+    match fs::create_dir_all(&dir) {
+        Ok(_) => return Some(dir),
+        Err(e) => {
+            println!("Error: Could not create {}: {}", dir.display(), e);
+            return None;
+        }
+    }
+}
+
+/**
+ * Read a pipe until it closes, in a thread
+ *
+ * returns the output read so far, and the thread, which is finished once the pipe is closed
+ */
+fn read_all_in_background<R: Read + Send + 'static>(
+    mut pipe: R,
+) -> (Arc<Mutex<Vec<u8>>>, thread::JoinHandle<()>) {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let buf = output.clone();
+    let reader = thread::spawn(move || {
+        let mut chunk = [0; 4096];
+        loop {
+            match pipe.read(&mut chunk) {
+                Ok(0) | Err(_) => return,
+                Ok(n) => buf.lock().unwrap().extend_from_slice(&chunk[..n]),
+            }
+        }
+    });
+    return (output, reader);
+}
+
+// how long the output is waited for once a timed out command was killed
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+/**
+ * Run a command in a directory, killing it once the timeout is reached. The command runs in its
+ * own process group, so that the program it starts(example: `cargo run` and the binary it builds)
+ * is killed with it.
+ */
+fn run_with_timeout(command: &[&str], dir: &Path, timeout: Duration) -> Option<RunResult> {
+    let mut child = match Command::new(command[0])
+        .args(&command[1..])
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            println!("Error: Could not run {}: {}", command[0], e);
+            return None;
+        }
+    };
+    // drain the pipes while waiting, so that a chatty program doesn't block on a full pipe
+    let stdout = read_all_in_background(child.stdout.take().unwrap());
+    let stderr = read_all_in_background(child.stderr.take().unwrap());
+
+    let start = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) => {
+                if start.elapsed() >= timeout {
+                    // the group's id is the id of its leader, the child
+                    unsafe {
+                        libc::killpg(child.id() as libc::pid_t, libc::SIGKILL);
+                    }
+                    let _ = child.wait();
+                    break None;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+            Err(e) => {
+                println!("Error: Could not wait for {}: {}", command[0], e);
+                return None;
+            }
+        }
+    };
+
+    // after a timeout, a process that left the group may still hold the pipes open: the output
+    // is only waited for a little while, and what was read by then is kept
+    let deadline = match status {
+        Some(_) => None,
+        None => Some(Instant::now() + KILL_GRACE),
+    };
+    let mut output = String::new();
+    for (buf, reader) in [stdout, stderr] {
+        match deadline {
+            Some(deadline) => {
+                while !reader.is_finished() && Instant::now() < deadline {
+                    thread::sleep(Duration::from_millis(50));
+                }
+            }
+            None => {
+                let _ = reader.join();
+            }
+        }
+        output.push_str(&String::from_utf8_lossy(&buf.lock().unwrap()));
+    }
+    let status = match status {
+        Some(status) => match status.code() {
+            Some(code) => format!("exit code {}", code),
+            None => "killed by a signal".to_string(),
+        },
+        None => format!("timed out after {}s", timeout.as_secs()),
+    };
+    return Some(RunResult { status, output });
+}
+
+/**
+ * How to run code blocks of a language: the built-in config, changed by the [run.<extension>]
+ * table of the config if there's one
+ */
+fn find_run_config(extension: &str) -> Result<RunConfig, String> {
+    let default = RUN_CONFIGS
+        .iter()
+        .find(|config| config.language_extension == extension);
+    let mut run_config = match default {
+        Some(default) => RunConfig {
+            files: default
+                .files
+                .iter()
+                .map(|(file_name, content)| (file_name.to_string(), *content))
+                .collect(),
+            command: default.command.iter().map(|arg| arg.to_string()).collect(),
+            timeout_secs: default.timeout_secs,
+        },
+        None => RunConfig {
+            files: Vec::new(),
+            command: Vec::new(),
+            timeout_secs: DEFAULT_TIMEOUT_SECS,
+        },
+    };
+    if let Some(settings) = config::get().run.get(extension) {
+        if let Some(file) = &settings.file {
+            // the code block moves to that file, the other files are kept
+            run_config.files.retain(|(_, content)| *content != CODE);
+            run_config.files.push((file.clone(), CODE));
+        }
+        if let Some(command) = &settings.command {
+            run_config.command = command.clone();
+        }
+        if let Some(timeout_secs) = settings.timeout_secs {
+            run_config.timeout_secs = timeout_secs;
+        }
+    }
+    if run_config.command.is_empty() || run_config.files.is_empty() {
+        return Err(format!(
+            "Don't know how to run {} code blocks, add a [run.{}] table with a command and a file \
+             to the config",
+            extension, extension
+        ));
+    }
+    return Ok(run_config);
+}
+
+/**
+ * Run a code block in a scratch directory in the system's temp dir, after confirmation
+ *
+ * @param label: the code block's number, used for the scratch directory's name
+ * returns the result of the run, None if it didn't run
+ */
+pub fn run_code_block(code_block: &CodeBlock, label: usize) -> Option<RunResult> {
+    let config = match find_run_config(&code_block.language_extension) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{}", e);
+            return None;
+        }
+    };
+
+    println!(
+        "Code block {} will be run with `{}` in a scratch directory",
+        label,
+        config.command.join(" ")
+    );
+    if !prompt::confirm("Run it?") {
+        return None;
+    }
+
+    let dir = create_scratch_dir(label)?;
+    for (file_name, content) in &config.files {
+        let path = dir.join(file_name);
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        let content = if *content == CODE {
+            code_block.code.as_str()
+        } else {
+            content
+        };
+        if let Err(e) = fs::write(&path, content) {
+            println!("Error: Could not write {}: {}", path.display(), e);
+            let _ = fs::remove_dir_all(&dir);
+            return None;
+        }
+    }
+
+    let command: Vec<&str> = config.command.iter().map(|arg| arg.as_str()).collect();
+    let result = run_with_timeout(&command, &dir, Duration::from_secs(config.timeout_secs));
+    let _ = fs::remove_dir_all(&dir);
+
+    if let Some(result) = &result {
+        print!("{}", result.output);
+        if !result.output.is_empty() && !result.output.ends_with('\n') {
+            println!();
+        }
+        println!("\x1b[2m[{}]\x1b[0m", result.status);
+    }
+    return result;
+}
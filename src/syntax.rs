@@ -1,84 +1,387 @@
+use lazy_static::lazy_static;
 use std::io::{self, Write};
 use syntect::easy::HighlightLines;
-use syntect::highlighting::{Style, ThemeSet};
-use syntect::parsing::SyntaxSet;
+use syntect::highlighting::{Style, Theme, ThemeSet};
+use syntect::html::highlighted_html_for_string;
+use syntect::parsing::{SyntaxReference, SyntaxSet};
 use syntect::util::as_24_bit_terminal_escaped;
+use unicode_segmentation::UnicodeSegmentation;
 
 use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::config;
+use crate::terminal;
+use crate::width;
+
+// theme = "auto" picks one of these, depending on the terminal's background
+const AUTO_THEME: &str = "auto";
+const DARK_THEME: &str = "base16-ocean.dark";
+const LIGHT_THEME: &str = "base16-ocean.light";
+
+// code the themes are previewed with
+const PREVIEW: &str = "/* Greet someone */
+fn greet(name: &str) -> String {
+    let count = 42; // the answer
+    return format!(\"Hello, {}! {}\", name, count);
+}
+";
+
+// loading the syntaxes and themes takes a while, so they're loaded once and shared. They're
+// loaded on first use, which may be in the middle of a json answer: errors go to stderr
+lazy_static! {
+    static ref SYNTAX_SET: SyntaxSet = load_syntaxes();
+    static ref THEME_SET: ThemeSet = load_themes();
+    static ref THEME: &'static Theme = find_theme(&config::get().theme);
+}
+
+/**
+ * A directory next to the config file, holding the user's themes or syntaxes
+ */
+fn user_dir(name: &str) -> PathBuf {
+    let mut dir = config::config_path();
+    dir.set_file_name(name);
+    return dir;
+}
+
+/**
+ * The default syntaxes, plus the user's .sublime-syntax definitions
+ */
+fn load_syntaxes() -> SyntaxSet {
+    let dir = user_dir("syntaxes");
+    if !dir.is_dir() {
+        return SyntaxSet::load_defaults_newlines();
+    }
+    let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+    match builder.add_from_folder(&dir, true) {
+        Ok(_) => return builder.build(),
+        Err(e) => {
+            eprintln!("Error: Could not load the syntaxes in {}: {}", dir.display(), e);
+            return SyntaxSet::load_defaults_newlines();
+        }
+    }
+}
+
+/**
+ * The default themes, plus the user's .tmTheme files, named after their file names
+ */
+fn load_themes() -> ThemeSet {
+    let mut themes = ThemeSet::load_defaults();
+    let dir = user_dir("themes");
+    if dir.is_dir() {
+        if let Err(e) = themes.add_from_folder(&dir) {
+            eprintln!("Error: Could not load the themes in {}: {}", dir.display(), e);
+        }
+    }
+    return themes;
+}
+
+/**
+ * The theme with that name, the dark or light default for "auto". Falls back to the dark default
+ * when there's no theme by that name.
+ */
+fn find_theme(name: &str) -> &'static Theme {
+    let name = match name {
+        AUTO_THEME if terminal::has_light_background() => LIGHT_THEME,
+        AUTO_THEME => DARK_THEME,
+        _ => name,
+    };
+    match THEME_SET.themes.get(name) {
+        Some(theme) => return theme,
+        None => {
+            eprintln!("Error: No theme named {}, \\themes lists them", name);
+            return &THEME_SET.themes[DARK_THEME];
+        }
+    }
+}
+
+/**
+ * Names of the themes, the user's included
+ */
+pub fn theme_names() -> Vec<String> {
+    return THEME_SET.themes.keys().cloned().collect();
+}
+
+/**
+ * The syntax for a code block: by its language's extension, then by its language tag so that the
+ * user's syntaxes are found by name(example: ```mydsl)
+ *
+ * @param extension: &str - The language's extension(example: "rs" for Rust)
+ * @param language: &str - The code block's language tag(example: "rust")
+ */
+fn find_syntax(extension: &str, language: &str) -> &'static SyntaxReference {
+    return SYNTAX_SET
+        .find_syntax_by_extension(extension)
+        .filter(|syntax| syntax.name != SYNTAX_SET.find_syntax_plain_text().name)
+        .or_else(|| SYNTAX_SET.find_syntax_by_token(language))
+        .unwrap_or_else(|| SYNTAX_SET.find_syntax_plain_text());
+}
+
+/**
+ * A highlighter for the lines of one code block, plain text if the language is unknown
+ *
+ * @param extension: &str - The language's extension(example: "rs" for Rust)
+ * @param language: &str - The code block's language tag(example: "rust")
+ */
+pub fn new_highlighter(extension: &str, language: &str) -> HighlightLines<'static> {
+    return HighlightLines::new(find_syntax(extension, language), &THEME);
+}
+
+/**
+ * Print a sample of code highlighted with each theme
+ *
+ * @param name: only preview the theme with that name, all of them if empty
+ */
+pub fn print_theme_previews(name: &str) {
+    let current: &Theme = &THEME;
+    let syntax = find_syntax("rs", "rust");
+    let mut found = false;
+    for (theme_name, theme) in THEME_SET.themes.iter() {
+        if !name.is_empty() && name != theme_name {
+            continue;
+        }
+        found = true;
+        match std::ptr::eq(theme, current) {
+            true => println!("\x1b[1m{}\x1b[0m (current)", theme_name),
+            false => println!("\x1b[1m{}\x1b[0m", theme_name),
+        }
+        let mut highlighter = HighlightLines::new(syntax, theme);
+        for line in PREVIEW.split_inclusive('\n') {
+            print_syntax_highlighted_code_line(&mut highlighter, line, None);
+        }
+        println!();
+    }
+    if !found {
+        println!("No theme named {}", name);
+    }
+}
 
 pub fn print_syntax_highlighted_code_block(code_block: &str, language: &str) {
-    let blocks = code_block.split("\n");
+    // lines keep their new line, which ends constructs like line comments
+    let blocks = code_block.split_inclusive('\n');
 
+    let mut highlighter = new_highlighter(language, language);
     for block in blocks {
-        print_syntax_highlighted_code_line(block, language, None);
+        print_syntax_highlighted_code_line(&mut highlighter, block, None);
     }
 }
 
 /**
  * Print syntax highlighted code to the terminal
  *
- * @param code: &str - The code to print. The code should be a single line
- * @param language: &str - The language's extension(example: "rs" for Rust)
+ * @param highlighter: the code block's highlighter, see `new_highlighter`. It carries the state of
+ * constructs spanning lines(example: a block comment) to the next line
+ * @param code: &str - The code to print. The code should be a single line, ending with its new line
  */
-pub fn print_syntax_highlighted_code_line(code: &str, language: &str, begin: Option<usize>) {
-    let s = get_syntax_highlighted_code_line(code, language, begin);
+pub fn print_syntax_highlighted_code_line(
+    highlighter: &mut HighlightLines,
+    code: &str,
+    begin: Option<usize>,
+) {
+    let s = get_syntax_highlighted_code_line(highlighter, code, begin);
     print!("{}", s);
     io::stdout().flush().unwrap();
 }
 
+pub fn get_syntax_highlighted_code_line(
+    highlighter: &mut HighlightLines,
+    code: &str,
+    begin: Option<usize>,
+) -> String {
+    let ranges: Vec<(Style, &str)> = highlighter.highlight(code, &SYNTAX_SET);
+    // println!("ranges: {:?}", ranges);
+
+    // skip the first `begin` bytes, rounded up to the next grapheme so that no character is split
+    let begin = match begin {
+        Some(begin) => code
+            .grapheme_indices(true)
+            .map(|(i, _)| i)
+            .find(|i| *i >= begin)
+            .unwrap_or(code.len()),
+        None => 0,
+    };
+    let mut ranges_post: Vec<(Style, &str)> = Vec::new();
+    let mut offset = 0;
+    for (style, text) in &ranges {
+        let end = offset + text.len();
+        if end > begin {
+            let from = begin.saturating_sub(offset);
+            ranges_post.push((*style, &text[from..]));
+        }
+        offset = end;
+    }
+    let mut escaped = match config::get().wrap_code {
+        true => wrap_ranges(&ranges_post, terminal::width()),
+        false => as_24_bit_terminal_escaped(&ranges_post[..], false),
+    };
 
-pub fn get_syntax_highlighted_code_line(code: &str, language: &str, begin: Option<usize>) -> String {
-    // Load the syntaxes and themes
-    // println!("printing |{} : {} |", language, code);
-    let ps = SyntaxSet::load_defaults_newlines();
-    let ts = ThemeSet::load_defaults();
-    // println!("=========================================");
+    escaped.push_str("\x1b[0m");
+    return escaped;
+}
 
-    // println!("beign: {:?}", begin);
-    // println!("code line: {}", code.len());
+// starts the rows a long code line is wrapped onto
+const CONTINUATION_MARKER: &str = "\x1b[0m\n\x1b[2m↪\x1b[0m ";
+const CONTINUATION_MARKER_WIDTH: usize = 2;
+
+/**
+ * Escape highlighted ranges, wrapping the rows longer than the width with a continuation marker
+ * instead of letting the terminal wrap them
+ */
+fn wrap_ranges(ranges: &[(Style, &str)], width: usize) -> String {
+    let width = std::cmp::max(width, CONTINUATION_MARKER_WIDTH + 1);
+    let mut escaped = String::new();
+    let mut column = 0;
+    for (style, text) in ranges {
+        let mut piece_start = 0;
+        for (i, grapheme) in text.grapheme_indices(true) {
+            if grapheme == "\n" || grapheme == "\r\n" {
+                column = 0;
+                continue;
+            }
+            let grapheme_width = width::grapheme_width(grapheme);
+            if column + grapheme_width > width {
+                let piece = [(*style, &text[piece_start..i])];
+                escaped.push_str(&as_24_bit_terminal_escaped(&piece, false));
+                escaped.push_str(CONTINUATION_MARKER);
+                piece_start = i;
+                column = CONTINUATION_MARKER_WIDTH;
+            }
+            column += grapheme_width;
+        }
+        let piece = [(*style, &text[piece_start..])];
+        escaped.push_str(&as_24_bit_terminal_escaped(&piece, false));
+    }
+    return escaped;
+}
 
-    // use C syntax if no syntax is found
+/**
+ * Render a code block as a standalone html snippet(a <pre> with inline styles)
+ *
+ * @param code: &str - The code to render, may span multiple lines
+ * @param language: &str - The language's extension(example: "rs" for Rust)
+ */
+pub fn get_syntax_highlighted_html(code: &str, language: &str) -> String {
+    let syntax = find_syntax(language, language);
 
-    let syntax = ps.find_syntax_by_extension(language).unwrap();
+    return highlighted_html_for_string(code, &SYNTAX_SET, syntax, &THEME);
+}
 
-    let mut h = HighlightLines::new(syntax, &ts.themes["base16-ocean.dark"]);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let ranges: Vec<(Style, &str)> = h.highlight(code, &ps);
-    // println!("ranges: {:?}", ranges);
+    /**
+     * The rows wrap_ranges drew, without their colors and continuation markers
+     */
+    fn wrapped_rows(ranges: &[(Style, &str)], width: usize) -> Vec<String> {
+        let escaped = wrap_ranges(ranges, width);
+        let mut visible = String::new();
+        let mut rest = escaped.as_str();
+        while let Some(start) = rest.find('\x1b') {
+            visible.push_str(&rest[..start]);
+            let end = rest[start..].find('m').unwrap();
+            rest = &rest[start + end + 1..];
+        }
+        visible.push_str(rest);
+        let rows: Vec<String> = visible
+            .split('\n')
+            .enumerate()
+            .map(|(i, row)| match i {
+                0 => row.to_string(),
+                _ => row.strip_prefix("↪ ").unwrap().to_string(),
+            })
+            .collect();
+        return rows;
+    }
 
-    let mut ranges_post = Vec::new();
-
-    let mut i: i16 = 0;
-
-    // println!("begin: {:?}", begin);
-
-    // TODO: this can be done more efficiently?
-    if let Some(begin) = begin {
-        let begin = begin as i16;
-        let mut start_appending = false;
-        for (style, text) in &ranges {
-            // println!("tex len: {}", text.len());
-            let text_len = text.len() as i16;
-            if i + text_len >= begin  {
-                // println!("i: {}, text_len: {}, begin: {}", i, text_len, begin);
-                let diff = i + text_len - begin;
-                let text_size = std::cmp::min(diff, text_len);
-                let slice_index = text_len - text_size;
-                // println!("diff: {}", diff);
-                // slice the cutoff text
-                // println!("slice_index: {}", slice_index);
-                ranges_post.push((style.clone(), &text[slice_index as usize..]));
-                start_appending = true;
+    #[test]
+    fn wrap_ranges_never_splits_a_grapheme() {
+        let family = "👨\u{200d}👩\u{200d}👧";
+        let code = format!("let s = \"e\u{301}中{}文a\u{301}\u{302}👍🏽\"; // 日本語", family);
+        let keyword = Style::default();
+        let string = Style {
+            font_style: syntect::highlighting::FontStyle::BOLD,
+            ..Style::default()
+        };
+        let ranges = [(keyword, &code[..8]), (string, &code[8..])];
+        // the marker and the widest grapheme fit in 4 columns
+        for width in 4..30 {
+            let rows = wrapped_rows(&ranges, width);
+            let graphemes: Vec<&str> = rows.iter().flat_map(|row| row.graphemes(true)).collect();
+            assert_eq!(graphemes, code.graphemes(true).collect::<Vec<&str>>(), "width {}", width);
+            for (i, row) in rows.iter().enumerate() {
+                let marker = if i == 0 { 0 } else { CONTINUATION_MARKER_WIDTH };
+                assert!(width::display_width(row) + marker <= width, "{:?} at {}", row, width);
             }
-            i += text_len;
         }
-        if !start_appending {
-            ranges_post = ranges.clone();
+    }
+
+    /**
+     * Highlight the lines one by one, like a streamed code block, and find the style of a word in
+     * each of them
+     */
+    fn styles_of(highlighter: &mut HighlightLines, lines: &[(&str, &str)]) -> Vec<Style> {
+        let mut styles = Vec::new();
+        for (line, word) in lines {
+            let ranges = highlighter.highlight(line, &SYNTAX_SET);
+            let at = line.find(word).unwrap();
+            let mut offset = 0;
+            for (style, text) in &ranges {
+                if offset <= at && at < offset + text.len() {
+                    styles.push(*style);
+                    break;
+                }
+                offset += text.len();
+            }
         }
+        return styles;
     }
-    // println!("ranges_post: {:?}", ranges_post);
-    let mut escaped = as_24_bit_terminal_escaped(&ranges_post[..], false);
 
-    escaped.push_str("\x1b[0m");
-    return escaped;
+    #[test]
+    fn block_comment_keeps_its_style_across_lines() {
+        let mut highlighter = new_highlighter("rs", "rust");
+        let styles = styles_of(
+            &mut highlighter,
+            &[
+                ("/* a comment\n", "comment"),
+                ("   spanning lines\n", "spanning"),
+                ("*/\n", "*/"),
+                ("let x = 1;\n", "let"),
+            ],
+        );
+        assert_eq!(styles[1], styles[0]);
+        assert_eq!(styles[2], styles[0]);
+        assert_ne!(styles[3], styles[0]);
+
+        // a block left open would swallow the next block, which gets a new highlighter
+        let lines = [("/* never closed\n", "never"), ("let x = 1;\n", "let")];
+        let leaked = styles_of(&mut new_highlighter("rs", "rust"), &lines);
+        assert_eq!(leaked[1], leaked[0]);
+        let next = styles_of(&mut new_highlighter("rs", "rust"), &lines[1..]);
+        assert_eq!(next[0], styles[3]);
+    }
+
+    #[test]
+    fn docstring_keeps_its_style_across_lines() {
+        let mut highlighter = new_highlighter("py", "python");
+        let styles = styles_of(
+            &mut highlighter,
+            &[
+                ("def f():\n", "def"),
+                ("    \"\"\"A docstring\n", "docstring"),
+                ("    spanning lines\n", "spanning"),
+                ("    \"\"\"\n", "\"\"\""),
+                ("    return 1\n", "return"),
+            ],
+        );
+        assert_eq!(styles[2], styles[1]);
+        assert_ne!(styles[2], styles[0]);
+        assert_ne!(styles[4], styles[1]);
+
+        let lines = [("\"\"\"never closed\n", "never"), ("def f():\n", "def")];
+        let leaked = styles_of(&mut new_highlighter("py", "python"), &lines);
+        assert_eq!(leaked[1], leaked[0]);
+        let next = styles_of(&mut new_highlighter("py", "python"), &lines[1..]);
+        assert_eq!(next[0], styles[0]);
+    }
 }
@@ -0,0 +1,53 @@
+use signal_hook::consts::SIGWINCH;
+use std::env;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use termion::terminal_size;
+
+const DEFAULT_WIDTH: usize = 80; // when the output isn't a terminal
+
+static RESIZED: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+static WIDTH: AtomicUsize = AtomicUsize::new(0); // 0 until the size is first queried
+
+/**
+ * Watch for terminal resizes(SIGWINCH). Until this is called, the size is queried once and never
+ * updated.
+ */
+pub fn init() {
+    let resized = RESIZED.get_or_init(|| Arc::new(AtomicBool::new(false)));
+    if let Err(e) = signal_hook::flag::register(SIGWINCH, resized.clone()) {
+        println!("Error: Could not watch for terminal resizes: {}", e);
+    }
+}
+
+/**
+ * Whether the terminal has a light background, going by the COLORFGBG variable some terminals set
+ * (example: "0;15" for black on white). A dark background is assumed when it isn't set.
+ */
+pub fn has_light_background() -> bool {
+    let colors = env::var("COLORFGBG").unwrap_or_default();
+    // the background is the last color, there may be a "default" in between
+    match colors.rsplit(';').next().map(|bg| bg.parse::<u8>()) {
+        Some(Ok(bg)) => return bg == 7 || (9..=15).contains(&bg),
+        _ => return false,
+    }
+}
+
+/**
+ * The terminal's width in columns. The size is cached and only queried again after a resize.
+ */
+pub fn width() -> usize {
+    let resized = RESIZED
+        .get()
+        .is_some_and(|resized| resized.swap(false, Ordering::Relaxed));
+    let width = WIDTH.load(Ordering::Relaxed);
+    if width > 0 && !resized {
+        return width;
+    }
+    let width = match terminal_size() {
+        Ok((columns, _)) if columns > 0 => columns as usize,
+        _ => DEFAULT_WIDTH,
+    };
+    WIDTH.store(width, Ordering::Relaxed);
+    return width;
+}
@@ -0,0 +1,117 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/**
+ * Columns a grapheme cluster takes in a terminal(example: 2 for "中" or "👍🏽", 1 for "é" even when
+ * it's an e followed by a combining accent)
+ */
+pub fn grapheme_width(grapheme: &str) -> usize {
+    return grapheme.width();
+}
+
+/**
+ * Length of the ANSI escape sequence at the start of the text, 0 if it doesn't start with one.
+ * Handles CSI sequences(colors, cursor moves) and OSC sequences(hyperlinks).
+ */
+fn escape_len(text: &str) -> usize {
+    let bytes = text.as_bytes();
+    if bytes.first() != Some(&0x1b) {
+        return 0;
+    }
+    match bytes.get(1).copied().unwrap_or(0) {
+        b'[' => {
+            // parameters, then a final byte in @..~
+            match bytes[2..].iter().position(|b| (0x40..=0x7e).contains(b)) {
+                Some(end) => return end + 3,
+                None => return bytes.len(),
+            }
+        }
+        b']' => {
+            // ended by BEL or ESC \
+            for i in 2..bytes.len() {
+                if bytes[i] == 0x07 {
+                    return i + 1;
+                }
+                if bytes[i] == 0x1b && bytes.get(i + 1) == Some(&b'\\') {
+                    return i + 2;
+                }
+            }
+            return bytes.len();
+        }
+        // a two character escape(example: ESC 7), or a lone ESC
+        _ => return 1 + text[1..].chars().next().map_or(0, |ch| ch.len_utf8()),
+    }
+}
+
+/**
+ * Columns text takes in a terminal, ANSI escape sequences take none
+ */
+pub fn display_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let visible = rest.find('\x1b').unwrap_or(rest.len());
+        width += rest[..visible]
+            .graphemes(true)
+            .map(grapheme_width)
+            .sum::<usize>();
+        rest = &rest[visible..];
+        let escape = escape_len(rest);
+        rest = &rest[escape..];
+    }
+    return width;
+}
+
+/**
+ * The longest start of the text that fits in `width` columns, cut on a grapheme boundary
+ */
+pub fn truncate(text: &str, width: usize) -> &str {
+    let mut used = 0;
+    for (i, grapheme) in text.grapheme_indices(true) {
+        used += grapheme_width(grapheme);
+        if used > width {
+            return &text[..i];
+        }
+    }
+    return text;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAMILY: &str = "👨\u{200d}👩\u{200d}👧"; // one grapheme, joined by zero width joiners
+    const E_ACUTE: &str = "e\u{301}"; // an e followed by a combining accent
+
+    #[test]
+    fn display_width_counts_columns() {
+        assert_eq!(display_width("abc"), 3);
+        assert_eq!(display_width("中文"), 4);
+        assert_eq!(display_width(FAMILY), 2);
+        assert_eq!(display_width(E_ACUTE), 1);
+        assert_eq!(display_width(&format!("caf{} 中 {}", E_ACUTE, FAMILY)), 10);
+    }
+
+    #[test]
+    fn display_width_skips_escape_sequences() {
+        assert_eq!(display_width("\x1b[1;31m中\x1b[0m"), 2);
+        assert_eq!(
+            display_width("\x1b]8;;https://example.com\x1b\\link\x1b]8;;\x1b\\"),
+            4
+        );
+    }
+
+    #[test]
+    fn truncate_keeps_whole_graphemes() {
+        assert_eq!(truncate("中文字", 3), "中");
+        assert_eq!(truncate("中文字", 4), "中文");
+        let text = format!("a{}b", FAMILY);
+        assert_eq!(truncate(&text, 2), "a");
+        assert_eq!(truncate(&text, 3), format!("a{}", FAMILY));
+        let text = format!("{}{}x", E_ACUTE, E_ACUTE);
+        assert_eq!(truncate(&text, 1), E_ACUTE);
+        assert_eq!(truncate(&text, 2), format!("{}{}", E_ACUTE, E_ACUTE));
+        assert_eq!(truncate("abc", 10), "abc");
+        assert_eq!(truncate("中", 1), "");
+    }
+}